@@ -0,0 +1,188 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::logger::Logger;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    pub bvid: String,
+    pub cid: i64,
+    /// Quality id of the downloaded video.
+    pub quality: u32,
+    /// Where the file was saved, only for reference as it may have been moved since.
+    pub path: PathBuf,
+    /// Other files saved along with it, e.g. the audio stream with `--no-merge`.
+    pub extra_paths: Vec<PathBuf>,
+}
+
+impl ArchiveEntry {
+    /// `path` followed by `extra_paths`.
+    pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.path).chain(&self.extra_paths)
+    }
+}
+
+/// Records of downloaded videos, one `<bvid> <cid> <quality> <path>` per line, the extra paths
+/// follow the path separated by tabs.
+pub struct Archive {
+    path: PathBuf,
+    entries: Vec<ArchiveEntry>,
+}
+
+fn parse_line(line: &str) -> Option<ArchiveEntry> {
+    let mut fields = line.splitn(4, ' ');
+    let bvid = fields.next()?.to_owned();
+    let cid = fields.next()?.parse().ok()?;
+    let quality = fields.next()?.parse().ok()?;
+    let mut paths = fields
+        .next()
+        .unwrap_or_default()
+        .split('\t')
+        .map(PathBuf::from);
+    Some(ArchiveEntry {
+        bvid,
+        cid,
+        quality,
+        path: paths.next().unwrap_or_default(),
+        extra_paths: paths.collect(),
+    })
+}
+
+impl Archive {
+    /// Reads the archive, it is fine if the file does not exist yet. Invalid lines are skipped
+    /// with a warning.
+    pub fn open(path: &Path, logger: &Logger) -> Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(anyhow!("failed to read {}: {e}", path.display())),
+        };
+        let entries = content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .filter_map(|(idx, line)| {
+                let entry = parse_line(line);
+                if entry.is_none() {
+                    logger.warn(&format!(
+                        "忽略 {} 第 {} 行无效的下载记录: '{line}'",
+                        path.display(),
+                        idx + 1
+                    ));
+                }
+                entry
+            })
+            .collect();
+        Ok(Archive {
+            path: path.to_owned(),
+            entries,
+        })
+    }
+
+    /// The latest record of the video part.
+    pub fn find(&self, bvid: &str, cid: i64) -> Option<&ArchiveEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.bvid == bvid && entry.cid == cid)
+    }
+
+    pub fn record(&mut self, entry: ArchiveEntry) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let paths: Vec<String> = entry
+            .paths()
+            // keep one record per line, and tabs for separating the paths
+            .map(|path| path.display().to_string().replace(['\n', '\t'], " "))
+            .collect();
+        writeln!(
+            file,
+            "{} {} {} {}",
+            entry.bvid,
+            entry.cid,
+            entry.quality,
+            paths.join("\t")
+        )?;
+        self.entries.push(entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tempdir::TempDir;
+
+    use super::{Archive, ArchiveEntry};
+    use crate::logger::Logger;
+
+    #[test]
+    fn record_and_reopen() {
+        let dir = TempDir::new("archive").unwrap();
+        let path = dir.path().join("archive.txt");
+        let mut archive = Archive::open(&path, &Logger::new(0)).unwrap();
+        assert!(archive.find("BV17x411w7KC", 279786).is_none());
+
+        for quality in [64, 80] {
+            archive
+                .record(ArchiveEntry {
+                    bvid: "BV17x411w7KC".to_owned(),
+                    cid: 279786,
+                    quality,
+                    path: PathBuf::from("download/a b.mp4"),
+                    extra_paths: vec![],
+                })
+                .unwrap();
+        }
+
+        let archive = Archive::open(&path, &Logger::new(0)).unwrap();
+        let entry = archive.find("BV17x411w7KC", 279786).unwrap();
+        assert_eq!(entry.quality, 80, "the latest record should be used");
+        assert_eq!(entry.path, PathBuf::from("download/a b.mp4"));
+        assert!(archive.find("BV17x411w7KC", 1).is_none());
+    }
+
+    #[test]
+    fn record_extra_paths() {
+        let dir = TempDir::new("archive").unwrap();
+        let path = dir.path().join("archive.txt");
+        let mut archive = Archive::open(&path, &Logger::new(0)).unwrap();
+        let entry = ArchiveEntry {
+            bvid: "BV17x411w7KC".to_owned(),
+            cid: 279786,
+            quality: 80,
+            path: PathBuf::from("download/a b.1080p.avc.mp4"),
+            extra_paths: vec![PathBuf::from("download/a b.319k.aac.m4a")],
+        };
+        archive.record(entry.clone()).unwrap();
+
+        let archive = Archive::open(&path, &Logger::new(0)).unwrap();
+        assert_eq!(archive.find("BV17x411w7KC", 279786), Some(&entry));
+    }
+
+    #[test]
+    fn skip_invalid_lines() {
+        let dir = TempDir::new("archive").unwrap();
+        let path = dir.path().join("archive.txt");
+        fs::write(
+            &path,
+            "BV17x411w7KC 279786 80 a.mp4\n\nBV17x411w7KC cid\nBV1mP4y1x7Ab 1002 64 b.mp4\n",
+        )
+        .unwrap();
+        let archive = Archive::open(&path, &Logger::new(0)).unwrap();
+        assert_eq!(archive.find("BV17x411w7KC", 279786).unwrap().quality, 80);
+        assert_eq!(
+            archive.find("BV1mP4y1x7Ab", 1002).unwrap().quality,
+            64,
+            "lines after an invalid one should be read"
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt::{self, Display};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The `{ code, message, ttl, data }` envelope shared by bilibili APIs.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiResponse<T> {
+    pub code: i64,
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub ttl: i64,
+    pub data: Option<T>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ApiError {
+    NotFound {
+        code: i64,
+        message: String,
+    },
+    RegionLocked {
+        code: i64,
+        message: String,
+    },
+    LoginRequired {
+        code: i64,
+        message: String,
+    },
+    VipRequired {
+        code: i64,
+        message: String,
+    },
+    PaidContent {
+        code: i64,
+        message: String,
+    },
+    RateLimited {
+        code: i64,
+        message: String,
+    },
+    Other {
+        code: i64,
+        message: String,
+    },
+    /// `code` is 0 but `data` is missing.
+    MissingData,
+}
+
+impl ApiError {
+    fn from_code(code: i64, message: String) -> Self {
+        match code {
+            -404 | 62002 | 62004 | 62012 => ApiError::NotFound { code, message },
+            // -10403 is shared by VIP only and region locked content
+            -10403 if message.contains("大会员") => ApiError::VipRequired { code, message },
+            -10403 | 6002003 => ApiError::RegionLocked { code, message },
+            -101 => ApiError::LoginRequired { code, message },
+            6002105 => ApiError::VipRequired { code, message },
+            87007 | 87008 => ApiError::PaidContent { code, message },
+            -412 | -509 | -799 => ApiError::RateLimited { code, message },
+            _ => ApiError::Other { code, message },
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::NotFound { code, message } => {
+                write!(f, "video not found or not visible ({code}: {message})")
+            }
+            ApiError::RegionLocked { code, message } => {
+                write!(
+                    f,
+                    "video is not available in your region ({code}: {message})"
+                )
+            }
+            ApiError::LoginRequired { code, message } => {
+                write!(f, "login required, run `login` first ({code}: {message})")
+            }
+            ApiError::VipRequired { code, message } => {
+                write!(f, "VIP membership required ({code}: {message})")
+            }
+            ApiError::PaidContent { code, message } => write!(
+                f,
+                "paid content, e.g. charging only, purchase it first ({code}: {message})"
+            ),
+            ApiError::RateLimited { code, message } => {
+                write!(f, "rate limited, try again later ({code}: {message})")
+            }
+            ApiError::Other { code, message } => write!(f, "api error ({code}: {message})"),
+            ApiError::MissingData => write!(f, "api response does not contain data"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+fn parse_envelope(body: &[u8]) -> Result<ApiResponse<serde_json::Value>> {
+    let response = serde_json::from_slice::<ApiResponse<serde_json::Value>>(body)?;
+    if response.code != 0 {
+        return Err(ApiError::from_code(response.code, response.message).into());
+    }
+    Ok(response)
+}
+
+/// Checks the envelope and deserializes `data`.
+pub fn parse_data<T: DeserializeOwned>(body: &[u8]) -> Result<T> {
+    let Some(data) = parse_envelope(body)?.data else {
+        return Err(ApiError::MissingData.into());
+    };
+    Ok(serde_json::from_value(data)?)
+}
+
+/// Checks the envelope of APIs that do not return `data`.
+pub fn check_response(body: &[u8]) -> Result<()> {
+    parse_envelope(body)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::{check_response, parse_data, ApiError};
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Data {
+        cid: i64,
+    }
+
+    fn api_error(body: &str) -> ApiError {
+        parse_data::<Data>(body.as_bytes())
+            .unwrap_err()
+            .downcast::<ApiError>()
+            .expect("should be an ApiError")
+    }
+
+    #[test]
+    fn parse_data_success() {
+        let body = r#"{"code":0,"message":"0","ttl":1,"data":{"cid":1}}"#;
+        assert_eq!(
+            parse_data::<Data>(body.as_bytes()).unwrap(),
+            Data { cid: 1 }
+        );
+    }
+
+    #[test]
+    fn parse_data_maps_error_codes() {
+        assert!(matches!(
+            api_error(r#"{"code":-404,"message":"啥都木有"}"#),
+            ApiError::NotFound { code: -404, .. }
+        ));
+        assert!(matches!(
+            api_error(r#"{"code":-10403,"message":"抱歉您所在地区不可观看！"}"#),
+            ApiError::RegionLocked { .. }
+        ));
+        assert!(matches!(
+            api_error(r#"{"code":-10403,"message":"大会员专享限制"}"#),
+            ApiError::VipRequired { .. }
+        ));
+        assert!(matches!(
+            api_error(r#"{"code":87008,"message":"当前为充电专属视频"}"#),
+            ApiError::PaidContent { .. }
+        ));
+        assert!(matches!(
+            api_error(r#"{"code":-101,"message":"账号未登录"}"#),
+            ApiError::LoginRequired { .. }
+        ));
+        assert!(matches!(
+            api_error(r#"{"code":-799,"message":"请求过于频繁"}"#),
+            ApiError::RateLimited { .. }
+        ));
+    }
+
+    #[test]
+    fn parse_data_missing_data() {
+        assert_eq!(
+            api_error(r#"{"code":0,"message":"0"}"#),
+            ApiError::MissingData
+        );
+        assert!(check_response(r#"{"code":0,"message":"0"}"#.as_bytes()).is_ok());
+    }
+}
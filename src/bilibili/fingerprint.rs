@@ -0,0 +1,125 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::api::parse_data;
+use crate::crawler::Fetching;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SpiDataSpec {
+    pub b_3: String,
+    pub b_4: String,
+}
+
+/// The device fingerprint cookies, requests without them are more likely to get 412.
+#[derive(Debug, Clone)]
+pub struct Fingerprint {
+    pub buvid3: String,
+    pub buvid4: String,
+    pub b_nut: String,
+}
+
+fn b_nut() -> String {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+        .to_string()
+}
+
+fn random_uuid() -> String {
+    let mut rng = rand::thread_rng();
+    let hex: String = (0..32)
+        .map(|_| format!("{:X}", rng.gen_range(0..16)))
+        .collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+impl Fingerprint {
+    /// Generates the fingerprint locally the same way the web player does.
+    pub fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        let suffix = format!("{:05}", rng.gen_range(0..100000));
+        Fingerprint {
+            buvid3: format!("{}{suffix}infoc", random_uuid()),
+            buvid4: format!("{}-{suffix}-0", random_uuid()),
+            b_nut: b_nut(),
+        }
+    }
+}
+
+pub async fn fetch_fingerprint<F: Fetching>(crawler: &F) -> Result<Fingerprint> {
+    let url = "https://api.bilibili.com/x/frontend/finger/spi";
+    let body_bytes = crawler.fetch_body(url).await?;
+    let data = parse_data::<SpiDataSpec>(&body_bytes)?;
+    Ok(Fingerprint {
+        buvid3: data.b_3,
+        buvid4: data.b_4,
+        b_nut: b_nut(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fetch_fingerprint, Fingerprint};
+    use crate::crawler::MockFetching;
+
+    fn is_uuid(s: &str) -> bool {
+        let groups: Vec<&str> = s.split('-').collect();
+        groups.iter().map(|g| g.len()).eq([8, 4, 4, 4, 12])
+            && groups.iter().all(|g| {
+                g.chars()
+                    .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase())
+            })
+    }
+
+    #[test]
+    fn generate_fingerprint() {
+        let fingerprint = Fingerprint::generate();
+        let buvid3 = fingerprint.buvid3.strip_suffix("infoc").unwrap();
+        let (uuid, suffix) = buvid3.split_at(36);
+        assert!(is_uuid(uuid), "unexpected buvid3 '{}'", fingerprint.buvid3);
+        assert_eq!(suffix.len(), 5);
+        assert!(suffix.chars().all(|c| c.is_ascii_digit()));
+
+        let buvid4 = fingerprint.buvid4.strip_suffix("-0").unwrap();
+        assert!(
+            is_uuid(&buvid4[..36]),
+            "unexpected buvid4 '{}'",
+            fingerprint.buvid4
+        );
+        assert_eq!(&buvid4[36..], format!("-{suffix}"));
+        assert!(fingerprint.b_nut.parse::<u64>().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn fetch_fingerprint_from_spi() {
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .withf(|url| url == "https://api.bilibili.com/x/frontend/finger/spi")
+            .times(1)
+            .returning(|_| {
+                Ok(br#"{"code":0,"data":{"b_3":"9D8E6A3C-0D5F-4A2B-B6C1-3E7F8A9B0C1D12345infoc","b_4":"F1E2D3C4-B5A6-9788-6950-413223140506-12345-0"},"message":"ok"}"#.to_vec())
+            });
+        let fingerprint = fetch_fingerprint(&crawler).await.unwrap();
+        assert_eq!(
+            fingerprint.buvid3,
+            "9D8E6A3C-0D5F-4A2B-B6C1-3E7F8A9B0C1D12345infoc"
+        );
+        assert_eq!(
+            fingerprint.buvid4,
+            "F1E2D3C4-B5A6-9788-6950-413223140506-12345-0"
+        );
+        assert!(!fingerprint.b_nut.is_empty());
+    }
+}
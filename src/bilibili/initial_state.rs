@@ -1,8 +1,5 @@
-use std::fmt::Debug;
-
 use anyhow::{anyhow, Result};
 use scraper::{Html, Selector};
-use serde::{Deserialize, Serialize};
 use swc_common::source_map::SmallPos;
 use swc_common::sync::Lrc;
 use swc_common::{
@@ -14,23 +11,6 @@ use swc_ecma_ast::{AssignTarget, Expr, MemberProp, Module, SimpleAssignTarget};
 use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax};
 use swc_ecmascript::visit::{Visit, VisitWith};
 
-#[derive(Serialize, Deserialize, Debug)]
-struct VideoDataSpec {
-    pub cid: i64,
-    pub bvid: String,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct InitialStateSpec {
-    pub video_data: VideoDataSpec,
-}
-
-pub struct InitialState {
-    pub cid: i64,
-    pub bvid: String,
-}
-
 fn parse_js(content: &str) -> Result<Module> {
     let cm: Lrc<SourceMap> = Default::default();
     let handler = Handler::with_tty_emitter(ColorConfig::Auto, true, false, Some(cm.clone()));
@@ -49,20 +29,18 @@ fn parse_js(content: &str) -> Result<Module> {
         e.into_diagnostic(&handler).emit();
     }
 
-    Ok(parser
+    parser
         .parse_module()
-        .map_err(|e| {
-            // Unrecoverable fatal error occurred
-            e.into_diagnostic(&handler).emit()
-        })
-        .expect("failed to parser module"))
+        .map_err(|e| anyhow!("failed to parse script: {}", e.kind().msg()))
 }
 
-struct InitialStateVisitor {
+/// Finds the object assigned in `window.<name> = {...}`.
+struct WindowAssignmentVisitor<'a> {
+    pub name: &'a str,
     pub object_span: Option<Span>,
 }
 
-impl Visit for InitialStateVisitor {
+impl<'a> Visit for WindowAssignmentVisitor<'a> {
     fn visit_assign_expr(&mut self, node: &swc_ecma_ast::AssignExpr) {
         let AssignTarget::Simple(simple_target) = &node.left else {
             return;
@@ -73,24 +51,27 @@ impl Visit for InitialStateVisitor {
         let Expr::Ident(ident) = *member_target.obj.clone() else {
             return;
         };
-        if ident.sym.to_string() != "window" {
+        if ident.sym != "window" {
             return;
         }
         let MemberProp::Ident(ident) = &member_target.prop else {
             return;
         };
-        if ident.sym.to_string() == "__INITIAL_STATE__" {
+        if ident.sym == self.name {
             self.object_span = Some(node.right.span());
         }
     }
 }
 
-fn initial_state_visitor() -> InitialStateVisitor {
-    InitialStateVisitor { object_span: None }
+fn window_assignment_visitor(name: &str) -> WindowAssignmentVisitor<'_> {
+    WindowAssignmentVisitor {
+        name,
+        object_span: None,
+    }
 }
 
-fn try_extract_from_code(ast: &Module, content: &str) -> Option<String> {
-    let mut visitor = initial_state_visitor();
+fn try_extract_from_code(ast: &Module, content: &str, name: &str) -> Option<String> {
+    let mut visitor = window_assignment_visitor(name);
     ast.visit_with(&mut visitor);
     if let Some(span) = visitor.object_span {
         let low = span.lo.to_usize() - 1;
@@ -101,20 +82,142 @@ fn try_extract_from_code(ast: &Module, content: &str) -> Option<String> {
     None
 }
 
-pub fn extract_initial_state(document: &Html) -> Result<InitialState> {
+/// Returns the end of the string literal starting at `start`, which is the opening quote.
+fn skip_string(bytes: &[u8], start: usize) -> Option<usize> {
+    let quote = bytes[start];
+    let mut idx = start + 1;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'\\' => idx += 2,
+            c if c == quote => return Some(idx + 1),
+            _ => idx += 1,
+        }
+    }
+    None
+}
+
+/// Returns the balanced `{...}` starting at `start`, braces inside strings are ignored.
+fn balanced_object(content: &str, start: usize) -> Option<&str> {
+    let bytes = content.as_bytes();
+    if bytes.get(start) != Some(&b'{') {
+        return None;
+    }
+    let mut depth = 0;
+    let mut idx = start;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'"' | b'\'' => {
+                idx = skip_string(bytes, idx)?;
+                continue;
+            }
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&content[start..=idx]);
+                }
+            }
+            _ => {}
+        }
+        idx += 1;
+    }
+    None
+}
+
+/// Scans for `window.<name>=` and takes the JSON object after it without parsing the script.
+fn try_extract_by_marker<'a>(content: &'a str, name: &str) -> Option<&'a str> {
+    let marker = format!("window.{name}");
+    let mut search_from = 0;
+    while let Some(found) = content[search_from..].find(&marker) {
+        let after_marker = search_from + found + marker.len();
+        search_from = after_marker;
+        let rest = content[after_marker..].trim_start();
+        let Some(value) = rest.strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let start = content.len() - value.len();
+        let Some(object) = balanced_object(content, start) else {
+            continue;
+        };
+        if serde_json::from_str::<serde::de::IgnoredAny>(object).is_ok() {
+            return Some(object);
+        }
+    }
+    None
+}
+
+/// Returns the source of the object assigned to `window.<name>` in the page scripts.
+pub(super) fn extract_window_assignment(document: &Html, name: &str) -> Result<Option<String>> {
     let script_selector =
         Selector::parse(r#"script:not([type*=json])"#).expect("failed to parse selector");
+    let mut parse_error = None;
     for script_element in document.select(&script_selector) {
         let script_content = script_element.text().collect::<Vec<_>>().join("");
-        let ast = parse_js(&script_content)?;
-        if let Some(json_string) = try_extract_from_code(&ast, &script_content) {
-            let initial_state = serde_json::from_str::<InitialStateSpec>(&json_string)
-                .expect("failed to parse initial state");
-            return Ok(InitialState {
-                bvid: initial_state.video_data.bvid,
-                cid: initial_state.video_data.cid,
-            });
+        if !script_content.contains(name) {
+            continue;
+        }
+        if let Some(json_string) = try_extract_by_marker(&script_content, name) {
+            return Ok(Some(json_string.to_owned()));
+        }
+        // the fast path failed, e.g. the value is not plain JSON, fallback to parse the script
+        let ast = match parse_js(&script_content) {
+            Ok(ast) => ast,
+            Err(e) => {
+                parse_error = Some(e);
+                continue;
+            }
+        };
+        if let Some(json_string) = try_extract_from_code(&ast, &script_content, name) {
+            return Ok(Some(json_string));
         };
     }
-    Err(anyhow!("failed to find __INITIAL_STATE__ assignment"))
+    match parse_error {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use scraper::Html;
+
+    use super::{extract_window_assignment, try_extract_by_marker};
+
+    #[test]
+    fn try_extract_by_marker_balanced_object() {
+        let script =
+            r#"window.__INITIAL_STATE__ = {"a":{"b":"}{\"'"},"c":[1]};(function(){var s;})()"#;
+        assert_eq!(
+            try_extract_by_marker(script, "__INITIAL_STATE__"),
+            Some(r#"{"a":{"b":"}{\"'"},"c":[1]}"#)
+        );
+    }
+
+    #[test]
+    fn try_extract_by_marker_not_json() {
+        assert_eq!(
+            try_extract_by_marker("window.__INITIAL_STATE__={a:1}", "__INITIAL_STATE__"),
+            None
+        );
+        assert_eq!(
+            try_extract_by_marker("window.__INITIAL_STATE__.a=1", "__INITIAL_STATE__"),
+            None
+        );
+    }
+
+    #[test]
+    fn extract_window_assignment_falls_back_to_parser() {
+        let html = Html::parse_document("<script>window.__INITIAL_STATE__={a:1,b:'x'};</script>");
+        assert_eq!(
+            extract_window_assignment(&html, "__INITIAL_STATE__").unwrap(),
+            Some("{a:1,b:'x'}".to_owned())
+        );
+    }
+
+    #[test]
+    fn extract_window_assignment_parse_error() {
+        let html = Html::parse_document("<script>window.__INITIAL_STATE__={a:</script>");
+        assert!(extract_window_assignment(&html, "__INITIAL_STATE__").is_err());
+    }
 }
@@ -0,0 +1,159 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::api::parse_data;
+use crate::crawler::Fetching;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct QrCodeDataSpec {
+    pub url: String,
+    pub qrcode_key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PollDataSpec {
+    pub code: i64,
+    #[serde(default)]
+    pub refresh_token: String,
+}
+
+pub struct QrCode {
+    /// The content to be encoded into the QR code.
+    pub url: String,
+    pub qrcode_key: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LoginCredentials {
+    pub sess_data: String,
+    pub bili_jct: String,
+    pub refresh_token: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QrCodeStatus {
+    NotScanned,
+    Scanned,
+    Expired,
+    Confirmed(LoginCredentials),
+}
+
+pub async fn request_qrcode<F: Fetching>(crawler: &F) -> Result<QrCode> {
+    let url = "https://passport.bilibili.com/x/passport-login/web/qrcode/generate";
+    let body_bytes = crawler.fetch_body(url).await?;
+    let data = parse_data::<QrCodeDataSpec>(&body_bytes)?;
+    Ok(QrCode {
+        url: data.url,
+        qrcode_key: data.qrcode_key,
+    })
+}
+
+pub(super) fn find_cookie(cookies: &[(String, String)], name: &str) -> Option<String> {
+    cookies
+        .iter()
+        .find(|(cookie_name, _)| cookie_name == name)
+        .map(|(_, value)| value.clone())
+}
+
+pub async fn poll_qrcode<F: Fetching>(crawler: &F, qrcode_key: &str) -> Result<QrCodeStatus> {
+    let url = format!(
+        "https://passport.bilibili.com/x/passport-login/web/qrcode/poll?qrcode_key={qrcode_key}"
+    );
+    let response = crawler.fetch_with_cookies(&url).await?;
+    let data = parse_data::<PollDataSpec>(&response.body)?;
+    match data.code {
+        0 => {
+            let sess_data = find_cookie(&response.cookies, "SESSDATA")
+                .ok_or_else(|| anyhow!("SESSDATA not found in the login response"))?;
+            let bili_jct = find_cookie(&response.cookies, "bili_jct")
+                .ok_or_else(|| anyhow!("bili_jct not found in the login response"))?;
+            Ok(QrCodeStatus::Confirmed(LoginCredentials {
+                sess_data,
+                bili_jct,
+                refresh_token: data.refresh_token,
+            }))
+        }
+        86101 => Ok(QrCodeStatus::NotScanned),
+        86090 => Ok(QrCodeStatus::Scanned),
+        86038 => Ok(QrCodeStatus::Expired),
+        code => Err(anyhow!("unexpected qrcode status: {code}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{poll_qrcode, request_qrcode, LoginCredentials, QrCodeStatus};
+    use crate::crawler::{FetchResponse, MockFetching};
+
+    async fn poll(code: i64, cookies: &[(&str, &str)]) -> QrCodeStatus {
+        let body = format!(
+            r#"{{"code":0,"message":"0","data":{{"url":"","refresh_token":"token","timestamp":0,"code":{code},"message":""}}}}"#
+        );
+        let cookies: Vec<(String, String)> = cookies
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_with_cookies()
+            .withf(|url| url.ends_with("qrcode_key=key"))
+            .returning(move |_| {
+                Ok(FetchResponse {
+                    body: body.clone().into_bytes(),
+                    cookies: cookies.clone(),
+                })
+            });
+        poll_qrcode(&crawler, "key").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn poll_qrcode_status() {
+        assert_eq!(poll(86101, &[]).await, QrCodeStatus::NotScanned);
+        assert_eq!(poll(86090, &[]).await, QrCodeStatus::Scanned);
+        assert_eq!(poll(86038, &[]).await, QrCodeStatus::Expired);
+    }
+
+    #[tokio::test]
+    async fn poll_qrcode_confirmed() {
+        let cookies = [
+            ("DedeUserID", "1"),
+            ("SESSDATA", "sess%2C1"),
+            ("bili_jct", "jct"),
+        ];
+        assert_eq!(
+            poll(0, &cookies).await,
+            QrCodeStatus::Confirmed(LoginCredentials {
+                sess_data: "sess%2C1".to_owned(),
+                bili_jct: "jct".to_owned(),
+                refresh_token: "token".to_owned(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_qrcode_confirmed_without_cookies() {
+        let mut crawler = MockFetching::new();
+        crawler.expect_fetch_with_cookies().returning(|_| {
+            Ok(FetchResponse {
+                body: br#"{"code":0,"data":{"code":0,"refresh_token":"token"}}"#.to_vec(),
+                cookies: vec![],
+            })
+        });
+        let error = poll_qrcode(&crawler, "key").await.unwrap_err();
+        assert!(error.to_string().contains("SESSDATA"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn request_qrcode_key() {
+        let mut crawler = MockFetching::new();
+        crawler.expect_fetch_body().returning(|_| {
+            Ok(
+                br#"{"code":0,"message":"0","data":{"url":"https://qr/1","qrcode_key":"key"}}"#
+                    .to_vec(),
+            )
+        });
+        let qrcode = request_qrcode(&crawler).await.unwrap();
+        assert_eq!(qrcode.url, "https://qr/1");
+        assert_eq!(qrcode.qrcode_key, "key");
+    }
+}
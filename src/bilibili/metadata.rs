@@ -0,0 +1,363 @@
+use anyhow::{anyhow, Result};
+use scraper::Html;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::initial_state::extract_window_assignment;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Owner {
+    pub mid: i64,
+    pub name: String,
+    pub face: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Dimension {
+    pub width: u32,
+    pub height: u32,
+    pub rotate: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Page {
+    pub cid: i64,
+    /// 1-based index of the part.
+    pub page: usize,
+    pub part: String,
+    pub duration: i64,
+    pub dimension: Dimension,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Stat {
+    pub view: i64,
+    pub danmaku: i64,
+    pub reply: i64,
+    pub favorite: i64,
+    pub coin: i64,
+    pub share: i64,
+    pub like: i64,
+}
+
+/// Flags of the video, 1 for yes and 0 for no.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Rights {
+    pub bp: u8,
+    pub elec: u8,
+    pub download: u8,
+    pub movie: u8,
+    pub pay: u8,
+    pub hd5: u8,
+    pub no_reprint: u8,
+    pub autoplay: u8,
+    pub ugc_pay: u8,
+    pub is_cooperation: u8,
+    pub is_stein_gate: u8,
+    pub is_360: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SeasonEpisode {
+    pub aid: i64,
+    pub bvid: String,
+    pub cid: i64,
+    pub title: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SeasonSection {
+    pub id: i64,
+    pub title: String,
+    pub episodes: Vec<SeasonEpisode>,
+}
+
+/// The collection (合集) the video belongs to.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct UgcSeason {
+    pub id: i64,
+    pub title: String,
+    pub cover: String,
+    pub mid: i64,
+    pub sections: Vec<SeasonSection>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SubtitleTrack {
+    pub id: i64,
+    pub lan: String,
+    pub lan_doc: String,
+    pub subtitle_url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Subtitle {
+    pub list: Vec<SubtitleTrack>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Tag {
+    pub tag_id: i64,
+    pub tag_name: String,
+}
+
+/// `videoData` in `__INITIAL_STATE__`, missing fields are left as default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+struct VideoDataSpec {
+    pub aid: i64,
+    pub bvid: String,
+    pub cid: i64,
+    pub title: String,
+    pub desc: String,
+    pub owner: Owner,
+    pub pubdate: i64,
+    pub duration: i64,
+    pub pages: Vec<Page>,
+    pub stat: Stat,
+    pub rights: Rights,
+    pub ugc_season: Option<UgcSeason>,
+    pub subtitle: Subtitle,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+struct MediaInfoSpec {
+    pub title: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+struct EpInfoSpec {
+    pub aid: i64,
+    pub bvid: String,
+    pub cid: i64,
+    pub title: String,
+    #[serde(alias = "longTitle")]
+    pub long_title: String,
+    pub duration: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct InitialStateSpec {
+    pub video_data: Option<VideoDataSpec>,
+    #[serde(default)]
+    pub tags: Vec<Tag>,
+    pub p: Option<usize>,
+    pub media_info: Option<MediaInfoSpec>,
+    pub ep_info: Option<EpInfoSpec>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct VideoMetadata {
+    pub aid: i64,
+    pub bvid: String,
+    /// cid of the selected part.
+    pub cid: i64,
+    pub title: String,
+    pub desc: String,
+    pub owner: Owner,
+    /// Unix timestamp in seconds.
+    pub pubdate: i64,
+    /// Duration of the whole video in seconds.
+    pub duration: i64,
+    /// 1-based index of the selected part.
+    pub page: usize,
+    pub pages: Vec<Page>,
+    pub stat: Stat,
+    pub rights: Rights,
+    pub ugc_season: Option<UgcSeason>,
+    pub subtitle: Subtitle,
+    pub tags: Vec<Tag>,
+}
+
+fn join_title(parts: &[&str]) -> String {
+    parts
+        .iter()
+        .map(|part| part.trim())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl VideoMetadata {
+    fn from_spec(spec: InitialStateSpec) -> Result<Self> {
+        if let Some(video_data) = spec.video_data {
+            let page = spec.p.unwrap_or(1);
+            // videoData.cid is always the cid of the first part
+            let cid = video_data
+                .pages
+                .get(page.saturating_sub(1))
+                .map(|p| p.cid)
+                .unwrap_or(video_data.cid);
+            return Ok(VideoMetadata {
+                aid: video_data.aid,
+                bvid: video_data.bvid,
+                cid,
+                title: video_data.title,
+                desc: video_data.desc,
+                owner: video_data.owner,
+                pubdate: video_data.pubdate,
+                duration: video_data.duration,
+                page,
+                pages: video_data.pages,
+                stat: video_data.stat,
+                rights: video_data.rights,
+                ugc_season: video_data.ugc_season,
+                subtitle: video_data.subtitle,
+                tags: spec.tags,
+            });
+        }
+        if let Some(ep_info) = spec.ep_info {
+            let media_title = spec.media_info.map(|m| m.title).unwrap_or_default();
+            return Ok(VideoMetadata {
+                aid: ep_info.aid,
+                bvid: ep_info.bvid,
+                cid: ep_info.cid,
+                title: join_title(&[&media_title, &ep_info.title, &ep_info.long_title]),
+                // duration of episodes is in milliseconds
+                duration: ep_info.duration / 1000,
+                page: 1,
+                tags: spec.tags,
+                ..Default::default()
+            });
+        }
+        Err(anyhow!(
+            "neither videoData nor epInfo is found in __INITIAL_STATE__"
+        ))
+    }
+
+    /// The selected part, `None` if the video has only one part.
+    pub fn part(&self) -> Option<&Page> {
+        if self.pages.len() > 1 {
+            self.pages.get(self.page.saturating_sub(1))
+        } else {
+            None
+        }
+    }
+
+    /// Duration of the selected part in seconds.
+    pub fn part_duration(&self) -> i64 {
+        match self.part() {
+            Some(part) => part.duration,
+            None => self.duration,
+        }
+    }
+
+    /// The video title with the part title of multi-part videos.
+    pub fn full_title(&self) -> String {
+        match self.part() {
+            Some(part) => join_title(&[&self.title, &format!("P{}", self.page), &part.part]),
+            None => join_title(&[&self.title]),
+        }
+    }
+}
+
+/// Removes object members that are `null`, so that they fall back to the defaults. The pages use
+/// `null` for many empty fields, e.g. `tags`, `subtitle` or `owner.face`.
+fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            object.retain(|_, member| !member.is_null());
+            object.values_mut().for_each(remove_nulls);
+        }
+        Value::Array(items) => items.iter_mut().for_each(remove_nulls),
+        _ => {}
+    }
+}
+
+pub fn extract_video_metadata(document: &Html) -> Result<VideoMetadata> {
+    let Some(json_string) = extract_window_assignment(document, "__INITIAL_STATE__")? else {
+        return Err(anyhow!("failed to find __INITIAL_STATE__ assignment"));
+    };
+    let mut value = serde_json::from_str::<Value>(&json_string)
+        .map_err(|e| anyhow!("failed to parse __INITIAL_STATE__: {e}"))?;
+    remove_nulls(&mut value);
+    let initial_state = serde_json::from_value::<InitialStateSpec>(value)
+        .map_err(|e| anyhow!("failed to parse __INITIAL_STATE__: {e}"))?;
+    VideoMetadata::from_spec(initial_state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_video_metadata, Owner};
+    use crate::fixtures;
+
+    #[test]
+    fn extract_video_metadata_single_part() {
+        let metadata = extract_video_metadata(&fixtures::html("video.html")).unwrap();
+        assert_eq!(metadata.aid, 170001);
+        assert_eq!(metadata.bvid, "BV17x411w7KC");
+        assert_eq!(metadata.cid, 279786);
+        assert_eq!(metadata.title, "【MV】保加利亚妖王AZIS视频合辑");
+        assert_eq!(
+            metadata.owner,
+            Owner {
+                mid: 58426,
+                name: "Cheer-up!".to_owned(),
+                face: "https://i0.hdslb.com/bfs/face/58426.jpg".to_owned(),
+            }
+        );
+        assert_eq!(metadata.pubdate, 1320850533);
+        assert_eq!(metadata.duration, 194);
+        assert_eq!(metadata.stat.view, 3741052);
+        assert_eq!(metadata.rights.download, 1);
+        assert_eq!(metadata.subtitle.list[0].lan, "zh-CN");
+        assert_eq!(metadata.tags[1].tag_name, "MV");
+        assert!(metadata.ugc_season.is_none());
+        assert!(metadata.part().is_none());
+        assert_eq!(metadata.full_title(), "【MV】保加利亚妖王AZIS视频合辑");
+    }
+
+    #[test]
+    fn extract_video_metadata_multi_part() {
+        let metadata = extract_video_metadata(&fixtures::html("video_multi_part.html")).unwrap();
+        assert_eq!(metadata.page, 2);
+        assert_eq!(
+            metadata.cid, 1002,
+            "cid of the selected part should be used"
+        );
+        assert_eq!(metadata.pages.len(), 3);
+        assert_eq!(metadata.part().unwrap().part, "第二集 & 花絮");
+        assert_eq!(metadata.full_title(), "多P视频 P2 第二集 & 花絮");
+        let season = metadata.ugc_season.unwrap();
+        assert_eq!(season.title, "合集·多P视频");
+        assert_eq!(season.sections[0].episodes[0].bvid, "BV1mP4y1x7Ab");
+        assert!(metadata.tags.is_empty(), "missing fields should be default");
+    }
+
+    #[test]
+    fn extract_video_metadata_bangumi() {
+        let metadata = extract_video_metadata(&fixtures::html("bangumi.html")).unwrap();
+        assert_eq!(metadata.bvid, "BV1Kx411r7Zn");
+        assert_eq!(metadata.cid, 5001);
+        assert_eq!(metadata.duration, 1420);
+        assert_eq!(metadata.full_title(), "某番剧 1 初次见面");
+    }
+
+    #[test]
+    fn extract_video_metadata_null_fields() {
+        let metadata = extract_video_metadata(&fixtures::html("video_null_fields.html")).unwrap();
+        assert_eq!(metadata.bvid, "BV1GJ411x7h7");
+        assert_eq!(metadata.cid, 137649199);
+        assert_eq!(metadata.desc, "");
+        assert_eq!(metadata.owner.face, "");
+        assert_eq!(metadata.stat.view, 0);
+        assert!(metadata.subtitle.list.is_empty());
+        assert!(metadata.tags.is_empty());
+        assert!(metadata.ugc_season.is_none());
+        assert_eq!(metadata.pages[0].dimension.width, 0);
+    }
+}
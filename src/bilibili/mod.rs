@@ -1,7 +1,17 @@
+mod api;
+mod fingerprint;
 mod initial_state;
+mod login;
+mod metadata;
+mod nav;
+mod refresh;
 mod title;
 mod video_info;
 
-pub use initial_state::extract_initial_state;
+pub use fingerprint::{fetch_fingerprint, Fingerprint};
+pub use login::{poll_qrcode, request_qrcode, LoginCredentials, QrCodeStatus};
+pub use metadata::{extract_video_metadata, VideoMetadata};
+pub use nav::{fetch_account, required_access, Access, Account};
+pub use refresh::{check_cookie_refresh, confirm_cookie_refresh, refresh_cookie};
 pub use title::extract_title;
-pub use video_info::fetch_video_info;
+pub use video_info::{extract_play_info, fetch_video_info, Resource, VideoInfo, VideoPreferences};
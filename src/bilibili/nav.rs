@@ -0,0 +1,122 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::api::{parse_data, ApiError};
+use crate::crawler::Fetching;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NavDataSpec {
+    pub is_login: bool,
+    #[serde(default)]
+    pub uname: String,
+    #[serde(default)]
+    pub vip_status: u8,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Account {
+    pub is_login: bool,
+    pub uname: String,
+    pub is_vip: bool,
+}
+
+/// The minimum account level required to get a quality.
+#[derive(Debug, PartialEq, PartialOrd)]
+pub enum Access {
+    Anonymous,
+    Login,
+    Vip,
+}
+
+impl Account {
+    pub fn access(&self) -> Access {
+        if self.is_vip {
+            Access::Vip
+        } else if self.is_login {
+            Access::Login
+        } else {
+            Access::Anonymous
+        }
+    }
+}
+
+pub fn required_access(quality: u8) -> Access {
+    match quality {
+        0..=32 => Access::Anonymous,
+        33..=80 => Access::Login,
+        _ => Access::Vip,
+    }
+}
+
+pub async fn fetch_account<F: Fetching>(crawler: &F) -> Result<Account> {
+    let url = "https://api.bilibili.com/x/web-interface/nav";
+    let body_bytes = crawler.fetch_body(url).await?;
+    let data = match parse_data::<NavDataSpec>(&body_bytes) {
+        Ok(data) => data,
+        Err(e) if matches!(e.downcast_ref(), Some(ApiError::LoginRequired { .. })) => {
+            return Ok(Account::default());
+        }
+        Err(e) => return Err(e),
+    };
+    Ok(Account {
+        is_login: data.is_login,
+        uname: data.uname,
+        is_vip: data.is_login && data.vip_status == 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fetch_account, required_access, Access};
+    use crate::crawler::MockFetching;
+
+    fn nav_crawler(body: &'static str) -> MockFetching {
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .withf(|url| url.ends_with("/x/web-interface/nav"))
+            .returning(move |_| Ok(body.as_bytes().to_vec()));
+        crawler
+    }
+
+    #[tokio::test]
+    async fn fetch_anonymous_account() {
+        let crawler = nav_crawler(
+            r#"{"code":-101,"message":"账号未登录","ttl":1,"data":{"isLogin":false,"wbi_img":{}}}"#,
+        );
+        let account = fetch_account(&crawler).await.unwrap();
+        assert!(!account.is_login);
+        assert_eq!(account.access(), Access::Anonymous);
+    }
+
+    #[tokio::test]
+    async fn fetch_vip_account() {
+        let crawler = nav_crawler(
+            r#"{"code":0,"message":"0","ttl":1,"data":{"isLogin":true,"uname":"user","vipStatus":1}}"#,
+        );
+        let account = fetch_account(&crawler).await.unwrap();
+        assert_eq!(account.uname, "user");
+        assert!(account.is_vip);
+        assert_eq!(account.access(), Access::Vip);
+
+        let crawler = nav_crawler(
+            r#"{"code":0,"message":"0","ttl":1,"data":{"isLogin":true,"uname":"user","vipStatus":0}}"#,
+        );
+        let account = fetch_account(&crawler).await.unwrap();
+        assert!(!account.is_vip);
+        assert_eq!(account.access(), Access::Login);
+    }
+
+    #[test]
+    fn required_access_by_quality() {
+        assert_eq!(required_access(16), Access::Anonymous);
+        assert_eq!(required_access(32), Access::Anonymous);
+        assert_eq!(required_access(64), Access::Login);
+        assert_eq!(required_access(80), Access::Login);
+        assert_eq!(required_access(112), Access::Vip);
+        assert_eq!(required_access(116), Access::Vip);
+        assert_eq!(required_access(120), Access::Vip);
+        assert!(Access::Anonymous < Access::Login && Access::Login < Access::Vip);
+    }
+}
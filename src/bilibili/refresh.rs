@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Result};
+use rsa::{pkcs8::DecodePublicKey, Oaep, RsaPublicKey};
+use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::{
+    api::{check_response, parse_data},
+    login::{find_cookie, LoginCredentials},
+};
+use crate::crawler::Fetching;
+
+const CORRESPOND_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDLgd2OAkcGVtoE3ThUREbio0Eg
+Uc/prcajMKXvkCKFCWhJYJcLkcM2DKKcSeFpD/j6Boy538YXnR6VhcuUJOhH2x71
+nzPjfdTcqMz7djHum0qSZA0AyCBDABUqCrfNgCiJ00Ra7GmRj+YCK1NJEuewlb40
+JNrRuoEUXpabUzGB8QIDAQAB
+-----END PUBLIC KEY-----";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CookieInfoDataSpec {
+    pub refresh: bool,
+    pub timestamp: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RefreshDataSpec {
+    pub refresh_token: String,
+}
+
+/// Returns the server timestamp to refresh with if the cookie has to be refreshed.
+pub async fn check_cookie_refresh<F: Fetching>(crawler: &F, bili_jct: &str) -> Result<Option<i64>> {
+    let url =
+        format!("https://passport.bilibili.com/x/passport-login/web/cookie/info?csrf={bili_jct}");
+    let body_bytes = crawler.fetch_body(&url).await?;
+    let data = parse_data::<CookieInfoDataSpec>(&body_bytes)?;
+    Ok(if data.refresh {
+        Some(data.timestamp)
+    } else {
+        None
+    })
+}
+
+fn correspond_path(timestamp: i64) -> Result<String> {
+    let public_key = RsaPublicKey::from_public_key_pem(CORRESPOND_PUBLIC_KEY)?;
+    encrypt_correspond_path(&public_key, timestamp)
+}
+
+/// RSA-OAEP encrypts `refresh_{timestamp}` and hex encodes it.
+fn encrypt_correspond_path(public_key: &RsaPublicKey, timestamp: i64) -> Result<String> {
+    let message = format!("refresh_{timestamp}");
+    let encrypted = public_key.encrypt(
+        &mut rand::thread_rng(),
+        Oaep::new::<Sha256>(),
+        message.as_bytes(),
+    )?;
+    Ok(encrypted.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+fn extract_refresh_csrf(document: &Html) -> Result<String> {
+    let selector = Selector::parse(r#"div[id="1-name"]"#).expect("failed to parse selector");
+    let Some(element) = document.select(&selector).next() else {
+        return Err(anyhow!("refresh_csrf not found in the correspond page"));
+    };
+    Ok(element.text().collect::<String>().trim().to_owned())
+}
+
+async fn fetch_refresh_csrf<F: Fetching>(crawler: &F, timestamp: i64) -> Result<String> {
+    let url = format!(
+        "https://www.bilibili.com/correspond/1/{}",
+        correspond_path(timestamp)?
+    );
+    let body_bytes = crawler.fetch_body(&url).await?;
+    let document = Html::parse_document(std::str::from_utf8(&body_bytes)?);
+    extract_refresh_csrf(&document)
+}
+
+/// Exchanges the refresh token for a new set of cookies. The old cookies stop working once this
+/// succeeds, so the new ones should be saved before calling `confirm_cookie_refresh`.
+pub async fn refresh_cookie<F: Fetching>(
+    crawler: &F,
+    credentials: &LoginCredentials,
+    timestamp: i64,
+) -> Result<LoginCredentials> {
+    let refresh_csrf = fetch_refresh_csrf(crawler, timestamp).await?;
+    let form = vec![
+        ("csrf".to_owned(), credentials.bili_jct.clone()),
+        ("refresh_csrf".to_owned(), refresh_csrf),
+        ("source".to_owned(), "main_web".to_owned()),
+        (
+            "refresh_token".to_owned(),
+            credentials.refresh_token.clone(),
+        ),
+    ];
+    let response = crawler
+        .post_form(
+            "https://passport.bilibili.com/x/passport-login/web/cookie/refresh",
+            &form,
+        )
+        .await?;
+    let data = parse_data::<RefreshDataSpec>(&response.body)?;
+    let sess_data = find_cookie(&response.cookies, "SESSDATA")
+        .ok_or_else(|| anyhow!("SESSDATA not found in the refresh response"))?;
+    let bili_jct = find_cookie(&response.cookies, "bili_jct")
+        .ok_or_else(|| anyhow!("bili_jct not found in the refresh response"))?;
+    Ok(LoginCredentials {
+        sess_data,
+        bili_jct,
+        refresh_token: data.refresh_token,
+    })
+}
+
+/// Invalidates the old refresh token. `crawler` must already use the refreshed SESSDATA.
+pub async fn confirm_cookie_refresh<F: Fetching>(
+    crawler: &F,
+    new_bili_jct: &str,
+    old_refresh_token: &str,
+) -> Result<()> {
+    let form = vec![
+        ("csrf".to_owned(), new_bili_jct.to_owned()),
+        ("refresh_token".to_owned(), old_refresh_token.to_owned()),
+    ];
+    let response = crawler
+        .post_form(
+            "https://passport.bilibili.com/x/passport-login/web/confirm/refresh",
+            &form,
+        )
+        .await?;
+    check_response(&response.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::{Oaep, RsaPrivateKey, RsaPublicKey};
+    use scraper::Html;
+    use sha2::Sha256;
+
+    use super::{correspond_path, encrypt_correspond_path, extract_refresh_csrf};
+
+    fn decode_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn correspond_path_decrypts_to_timestamp() {
+        let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let public_key = RsaPublicKey::from(&private_key);
+        let path = encrypt_correspond_path(&public_key, 1684466082768).unwrap();
+        let decrypted = private_key
+            .decrypt(Oaep::new::<Sha256>(), &decode_hex(&path))
+            .unwrap();
+        assert_eq!(decrypted, b"refresh_1684466082768");
+    }
+
+    #[test]
+    fn correspond_path_with_bilibili_key() {
+        let path = correspond_path(1684466082768).unwrap();
+        assert_eq!(path.len(), 256, "a 1024-bit key encrypts to 128 bytes");
+        assert!(path
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+        assert_ne!(
+            path,
+            correspond_path(1684466082768).unwrap(),
+            "OAEP should be randomized"
+        );
+    }
+
+    #[test]
+    fn extract_refresh_csrf_from_correspond_page() {
+        let html = r#"<!DOCTYPE html><html><body>
+            <div id="1-name">
+                b0cc8411ded2f9db2cff2edb3123acac
+            </div>
+            <div id="1-done">ok</div>
+        </body></html>"#;
+        assert_eq!(
+            extract_refresh_csrf(&Html::parse_document(html)).unwrap(),
+            "b0cc8411ded2f9db2cff2edb3123acac"
+        );
+        assert!(extract_refresh_csrf(&Html::parse_document("<html></html>")).is_err());
+    }
+}
@@ -1,20 +1,93 @@
 use anyhow::{anyhow, Result};
 use scraper::{Html, Selector};
 
-pub fn extract_title(document: &Html, video_id: &str) -> Result<String> {
+use super::metadata::VideoMetadata;
+
+const TITLE_SUFFIXES: [&str; 3] = ["_哔哩哔哩_bilibili", "_bilibili", "-bilibili"];
+
+fn normalize(text: &str) -> Option<String> {
+    let title = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if title.is_empty() {
+        None
+    } else {
+        Some(title)
+    }
+}
+
+fn title_from_meta(document: &Html) -> Option<String> {
+    let meta_selector = Selector::parse(r#"meta[property="og:title"]"#).unwrap();
+    let content = document
+        .select(&meta_selector)
+        .find_map(|element| element.value().attr("content"))?;
+    let title = TITLE_SUFFIXES
+        .iter()
+        .find_map(|suffix| content.strip_suffix(suffix))
+        .unwrap_or(content);
+    normalize(title)
+}
+
+fn title_from_h1(document: &Html) -> Option<String> {
     let title_selector = Selector::parse("h1").unwrap();
-    let mut potential_titles: Vec<String> = Vec::new();
-    for title_element in document.select(&title_selector) {
-        potential_titles.push(title_element.inner_html());
+    document
+        .select(&title_selector)
+        .find_map(|element| normalize(&element.text().collect::<String>()))
+}
+
+/// Finds the title from the video metadata, then `og:title` and finally the first `<h1>`.
+pub fn extract_title(document: &Html, metadata: &VideoMetadata, video_id: &str) -> Result<String> {
+    normalize(&metadata.full_title())
+        .or_else(|| title_from_meta(document))
+        .or_else(|| title_from_h1(document))
+        .ok_or_else(|| anyhow!("no title found in the page '{}'", video_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use scraper::Html;
+
+    use super::extract_title;
+    use crate::bilibili::metadata::VideoMetadata;
+
+    fn metadata(title: Option<&str>) -> VideoMetadata {
+        VideoMetadata {
+            cid: 1,
+            bvid: "BV1".to_owned(),
+            title: title.unwrap_or_default().to_owned(),
+            ..Default::default()
+        }
     }
-    if potential_titles.len() > 1 {
-        return Err(anyhow!(
-            "multiple <h1> tag found in the page '{}'",
-            video_id,
-        ));
-    } else if potential_titles.is_empty() {
-        return Err(anyhow!("no <h1> tag found in the page '{}'", video_id));
-    } else {
-        return Ok(potential_titles[0].to_string());
+
+    #[test]
+    fn extract_title_prefers_initial_state() {
+        let html = Html::parse_document("<h1>h1 title</h1>");
+        let title = extract_title(&html, &metadata(Some(" a  &amp; b ")), "BV1").unwrap();
+        assert_eq!(
+            title, "a &amp; b",
+            "title from json should be kept as it is"
+        );
+    }
+
+    #[test]
+    fn extract_title_from_meta() {
+        let html = Html::parse_document(
+            r#"<meta property="og:title" content="Tom &amp; Jerry_哔哩哔哩_bilibili"><h1>h1</h1>"#,
+        );
+        let title = extract_title(&html, &metadata(None), "BV1").unwrap();
+        assert_eq!(title, "Tom & Jerry");
+    }
+
+    #[test]
+    fn extract_title_from_h1() {
+        let html = Html::parse_document(
+            "<h1></h1><h1 title=\"x\"> <span>1 &lt; 2</span>\n &amp; 3 </h1><h1>other</h1>",
+        );
+        let title = extract_title(&html, &metadata(None), "BV1").unwrap();
+        assert_eq!(title, "1 < 2 & 3");
+    }
+
+    #[test]
+    fn extract_title_not_found() {
+        let html = Html::parse_document("<p>nothing</p>");
+        assert!(extract_title(&html, &metadata(None), "BV1").is_err());
     }
 }
@@ -1,12 +1,17 @@
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use anyhow::{anyhow, Result};
+use scraper::Html;
+use serde::{Deserialize, Deserializer, Serialize};
 
+use super::{api::parse_data, initial_state::extract_window_assignment};
 use crate::crawler::Fetching;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AudioSpec {
+    pub id: u32,
     pub base_url: String,
     pub bandwidth: u32,
+    #[serde(default)]
+    pub codecs: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -14,112 +19,390 @@ pub struct VideoSpec {
     pub id: u8,
     pub base_url: String,
     pub bandwidth: u32,
+    #[serde(default)]
+    pub codecs: String,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    #[serde(default)]
+    pub frame_rate: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DashSpec {
+    /// In seconds.
+    #[serde(default)]
+    pub duration: u64,
     pub video: Vec<VideoSpec>,
     pub audio: Vec<AudioSpec>,
+    /// Dolby Atmos (E-AC-3) audio, only for VIP.
+    #[serde(default)]
+    pub dolby: Option<DolbySpec>,
+    /// Hi-Res lossless audio, only for VIP.
+    #[serde(default)]
+    pub flac: Option<FlacSpec>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct DataSpec {
-    pub accept_description: Vec<String>,
-    pub accept_quality: Vec<u8>,
-    pub dash: DashSpec,
+pub struct DolbySpec {
+    #[serde(default)]
+    pub audio: Option<Vec<AudioSpec>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FlacSpec {
+    #[serde(default)]
+    pub audio: Option<AudioSpec>,
+}
+
+/// Flags are either booleans or 0/1 depending on the endpoint.
+fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Bool(flag) => flag,
+        serde_json::Value::Number(n) => n.as_i64().unwrap_or(0) != 0,
+        _ => false,
+    })
 }
 
+/// A segment of a progressive (FLV/MP4) stream.
 #[derive(Serialize, Deserialize, Debug)]
-pub struct VideoInfoSpec {
-    pub data: DataSpec,
+pub struct DurlSpec {
+    /// In milliseconds.
+    #[serde(default)]
+    pub length: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DataSpec {
+    /// Set for trial clips of VIP-only content.
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub is_preview: bool,
+    pub accept_description: Vec<String>,
+    pub accept_quality: Vec<u8>,
+    #[serde(default)]
+    pub dash: Option<DashSpec>,
+    /// Set instead of `dash` if only a progressive stream is available, e.g. for some trial clips.
+    #[serde(default)]
+    pub durl: Option<Vec<DurlSpec>>,
 }
 
 #[derive(Clone)]
 pub struct Resource {
+    /// Quality id for videos, e.g. 80 for 1080P, and audio id for audios, e.g. 30280.
+    pub id: u32,
     pub base_url: String,
+    /// In bits per second.
     pub bandwidth: u32,
+    pub codecs: String,
+    /// 0 for audios.
+    pub width: u32,
+    /// 0 for audios.
+    pub height: u32,
+    /// 0 for audios.
+    pub frame_rate: f64,
+}
+
+/// Quality ids of the HDR variants, which look washed out on players without HDR support.
+const QUALITY_HDR: u32 = 125;
+const QUALITY_DOLBY_VISION: u32 = 126;
+
+#[derive(Debug, Clone)]
+pub struct VideoPreferences {
+    /// Prefer 60fps variants like 1080P60 over the 30fps ones of the same resolution.
+    pub prefer_high_fps: bool,
+    pub allow_hdr: bool,
+    pub allow_dolby_vision: bool,
+}
+
+impl Default for VideoPreferences {
+    fn default() -> Self {
+        VideoPreferences {
+            prefer_high_fps: true,
+            allow_hdr: false,
+            allow_dolby_vision: false,
+        }
+    }
+}
+
+impl VideoPreferences {
+    pub fn allows(&self, quality: u32) -> bool {
+        match quality {
+            QUALITY_HDR => self.allow_hdr,
+            QUALITY_DOLBY_VISION => self.allow_dolby_vision,
+            _ => true,
+        }
+    }
+
+    /// Sort key of videos, the larger the better.
+    pub fn rank(&self, video: &Resource) -> (u32, i64, u32, u32) {
+        let fps = (video.frame_rate * 1000.0) as i64;
+        (
+            video.height,
+            if self.prefer_high_fps { fps } else { -fps },
+            video.id,
+            video.bandwidth,
+        )
+    }
+
+    /// Sort key of quality ids following `rank`, for comparing with the archive that only has
+    /// the id. Ids are not ordered by quality: HDR variants that are not allowed rank below
+    /// all others, and 1080P60 (116) ranks below 1080P+ (112) if high frame rates are not
+    /// preferred.
+    pub fn quality_rank(&self, quality: u32) -> (bool, u32, i64, u32) {
+        let (height, fps) = match quality {
+            127 => (4320, 30),
+            120 | QUALITY_HDR | QUALITY_DOLBY_VISION => (2160, 30),
+            116 => (1080, 60),
+            80 | 112 => (1080, 30),
+            74 => (720, 60),
+            64 => (720, 30),
+            32 => (480, 30),
+            16 => (360, 30),
+            6 => (240, 30),
+            _ => (0, 0),
+        };
+        (
+            self.allows(quality),
+            height,
+            if self.prefer_high_fps { fps } else { -fps },
+            quality,
+        )
+    }
 }
 
 pub struct VideoInfo {
     pub accept_description: Vec<String>,
     pub accept_quality: Vec<u8>,
+    /// In seconds.
+    pub duration: u64,
+    pub is_preview: bool,
     pub video: Vec<Resource>,
     pub audio: Vec<Resource>,
 }
 
-pub async fn fetch_video_info<'a, F: Fetching>(
-    crawler: &F,
-    bvid: &str,
-    cid: i64,
-) -> Result<VideoInfo> {
-    let url = format!(
-        "https://api.bilibili.com/x/player/wbi/playurl?bvid={}&cid={}&fnval=4048",
-        bvid, cid
-    );
-    let body_bytes = crawler.fetch_body(&url).await?;
-    let body_str = std::str::from_utf8(&body_bytes)?;
-    let raw_info = serde_json::from_str::<VideoInfoSpec>(&body_str)?;
+fn video_info_from_spec(data: DataSpec) -> Result<VideoInfo> {
+    let Some(dash) = data.dash else {
+        return Err(match data.durl {
+            Some(durl) => anyhow!(
+                "only a progressive preview of {}s is available, which is not supported",
+                durl.iter().map(|d| d.length).sum::<u64>() / 1000
+            ),
+            None => anyhow!("no dash streams in the play info"),
+        });
+    };
     Ok(VideoInfo {
-        accept_description: raw_info.data.accept_description,
-        accept_quality: raw_info.data.accept_quality,
-        video: raw_info
-            .data
-            .dash
+        accept_description: data.accept_description,
+        accept_quality: data.accept_quality,
+        duration: dash.duration,
+        is_preview: data.is_preview,
+        video: dash
             .video
             .iter()
             .map(|v| Resource {
+                id: v.id.into(),
                 base_url: v.base_url.clone(),
                 bandwidth: v.bandwidth,
+                codecs: v.codecs.clone(),
+                width: v.width,
+                height: v.height,
+                frame_rate: v.frame_rate.parse().unwrap_or(0.0),
             })
             .collect(),
-        audio: raw_info
-            .data
-            .dash
+        audio: dash
             .audio
             .iter()
+            .chain(dash.dolby.iter().flat_map(|d| d.audio.iter().flatten()))
+            .chain(dash.flac.iter().flat_map(|f| f.audio.iter()))
             .map(|v| Resource {
+                id: v.id,
                 base_url: v.base_url.clone(),
                 bandwidth: v.bandwidth,
+                codecs: v.codecs.clone(),
+                width: 0,
+                height: 0,
+                frame_rate: 0.0,
             })
             .collect(),
     })
 }
 
+pub async fn fetch_video_info<F: Fetching>(crawler: &F, bvid: &str, cid: i64) -> Result<VideoInfo> {
+    let url = format!(
+        "https://api.bilibili.com/x/player/wbi/playurl?bvid={}&cid={}&fnval=4048",
+        bvid, cid
+    );
+    let body_bytes = crawler.fetch_body(&url).await?;
+    let data = parse_data::<DataSpec>(&body_bytes)?;
+    video_info_from_spec(data)
+}
+
+/// Reads the video info from `window.__playinfo__` embedded in the video page, which has the
+/// same content as the playurl API. Returns `None` if the page does not have it.
+pub fn extract_play_info(document: &Html) -> Result<Option<VideoInfo>> {
+    let Some(json_string) = extract_window_assignment(document, "__playinfo__")? else {
+        return Ok(None);
+    };
+    let data = parse_data::<DataSpec>(json_string.as_bytes())?;
+    video_info_from_spec(data).map(Some)
+}
+
 impl VideoInfo {
-    pub fn get_hightest_quality_name(&self) -> String {
-        let mut max_idx = 0;
-        let mut max_quality = 0;
-        for (idx, quality) in self.accept_quality.iter().enumerate() {
-            if *quality > max_quality {
-                max_quality = *quality;
-                max_idx = idx;
-            }
+    pub fn get_highest_quality(&self, preferences: &VideoPreferences) -> u8 {
+        self.accept_quality
+            .iter()
+            .copied()
+            .filter(|q| preferences.allows((*q).into()))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Whether the streams are only a trial clip of a video that lasts `full_duration` seconds.
+    pub fn is_preview(&self, full_duration: u64) -> bool {
+        if self.is_preview {
+            return true;
         }
-        self.accept_description[max_idx].clone()
+        // the dash duration is rounded, so allow some slack
+        self.duration > 0 && full_duration > 0 && self.duration + 10 < full_duration * 9 / 10
     }
 
-    fn find_best_resource(&self, resources: &[Resource]) -> (Resource, Resource) {
-        let mut max_bandwidth = 0;
-        let mut best_resource_idx = 0;
-        let mut second_best_resource_idx = 0;
-        for (idx, resource) in resources.iter().enumerate() {
-            if resource.bandwidth > max_bandwidth {
-                second_best_resource_idx = best_resource_idx;
-                max_bandwidth = resource.bandwidth;
-                best_resource_idx = idx;
-            }
+    /// Estimated size in bytes of a resource of the whole video.
+    pub fn estimate_size(&self, resource: &Resource) -> u64 {
+        resource.bandwidth as u64 * self.duration / 8
+    }
+
+    /// Videos allowed by the preferences.
+    pub fn allowed_videos<'a>(
+        &'a self,
+        preferences: &'a VideoPreferences,
+    ) -> impl Iterator<Item = &'a Resource> {
+        self.video.iter().filter(|v| preferences.allows(v.id))
+    }
+
+    pub fn get_quality_name(&self, quality: u8) -> String {
+        match self.accept_quality.iter().position(|q| *q == quality) {
+            Some(idx) => self.accept_description[idx].clone(),
+            None => format!("{quality}"),
         }
-        (
-            resources[best_resource_idx].clone(),
-            resources[second_best_resource_idx].clone(),
-        )
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use scraper::Html;
+
+    use super::{extract_play_info, VideoPreferences};
 
-    pub fn get_best_audio(&self) -> Resource {
-        self.find_best_resource(&self.audio).0
+    #[test]
+    fn extract_play_info_from_page() {
+        let html = Html::parse_document(
+            r#"<html><head>
+            <script>window.__INITIAL_STATE__={"videoData":{"cid":1,"bvid":"BV1"}};</script>
+            <script>window.__playinfo__={"code":0,"message":"0","ttl":1,"data":{
+                "accept_description":["高清 1080P","清晰 480P"],
+                "accept_quality":[80,32],
+                "dash":{
+                    "video":[{"id":80,"base_url":"https://v/80","bandwidth":2000},
+                             {"id":32,"base_url":"https://v/32","bandwidth":500}],
+                    "audio":[{"id":30280,"base_url":"https://a/30280","bandwidth":300}],
+                    "dolby":{"type":1,"audio":[
+                        {"id":30250,"base_url":"https://a/30250","bandwidth":800,"codecs":"ec-3"}]},
+                    "flac":{"display":true,"audio":
+                        {"id":30251,"base_url":"https://a/30251","bandwidth":900,"codecs":"fLaC"}}
+                }
+            }}</script>
+            </head><body></body></html>"#,
+        );
+        let video_info = extract_play_info(&html)
+            .unwrap()
+            .expect("__playinfo__ exists");
+        assert_eq!(video_info.accept_quality, vec![80, 32]);
+        assert_eq!(video_info.video.len(), 2);
+        assert_eq!(video_info.audio[0].id, 30280);
+        assert_eq!(video_info.audio[1].codecs, "ec-3");
+        assert_eq!(video_info.audio[2].id, 30251);
+        assert!(!video_info.is_preview(0));
     }
 
-    pub fn get_best_video(&self) -> Resource {
-        self.find_best_resource(&self.video).1
+    #[test]
+    fn detect_preview() {
+        let html = Html::parse_document(
+            r#"<html><head>
+            <script>window.__playinfo__={"code":0,"message":"0","ttl":1,"data":{
+                "accept_description":["高清 1080P"],
+                "accept_quality":[80],
+                "dash":{"duration":180,"video":[],"audio":[]}
+            }}</script>
+            </head></html>"#,
+        );
+        let video_info = extract_play_info(&html).unwrap().unwrap();
+        assert!(!video_info.is_preview(185));
+        assert!(video_info.is_preview(1420), "much shorter than the video");
+
+        let html = Html::parse_document(
+            r#"<html><head>
+            <script>window.__playinfo__={"code":0,"message":"0","ttl":1,"data":{
+                "is_preview":1,
+                "accept_description":[],
+                "accept_quality":[],
+                "dash":{"duration":1420,"video":[],"audio":[]}
+            }}</script>
+            </head></html>"#,
+        );
+        let video_info = extract_play_info(&html).unwrap().unwrap();
+        assert!(video_info.is_preview(1420));
+    }
+
+    #[test]
+    fn progressive_preview_is_rejected() {
+        let html = Html::parse_document(
+            r#"<html><head>
+            <script>window.__playinfo__={"code":0,"message":"0","ttl":1,"data":{
+                "is_preview":1,
+                "format":"mp4",
+                "timelength":360000,
+                "accept_description":["高清 1080P"],
+                "accept_quality":[80],
+                "durl":[{"order":1,"length":360000,"size":12345678,"url":"https://v/trial.mp4"}]
+            }}</script>
+            </head></html>"#,
+        );
+        let error = extract_play_info(&html)
+            .err()
+            .expect("durl is not supported");
+        assert!(
+            error.to_string().contains("progressive preview of 360s"),
+            "unexpected error: {error}"
+        );
+    }
+
+    #[test]
+    fn extract_play_info_missing() {
+        let html = Html::parse_document(
+            r#"<html><head><script>window.__INITIAL_STATE__={};</script></head></html>"#,
+        );
+        assert!(extract_play_info(&html).unwrap().is_none());
+    }
+
+    #[test]
+    fn quality_rank() {
+        let preferences = VideoPreferences::default();
+        let rank = |quality| preferences.quality_rank(quality);
+        assert!(rank(116) > rank(112));
+        assert!(rank(80) > rank(74));
+        // HDR is not allowed by default, so 4K is better although its id is lower
+        assert!(rank(120) > rank(125));
+        assert!(rank(120) > rank(126));
+        assert!(rank(80) > rank(126));
+
+        let preferences = VideoPreferences {
+            prefer_high_fps: false,
+            allow_hdr: true,
+            allow_dolby_vision: true,
+        };
+        let rank = |quality| preferences.quality_rank(quality);
+        assert!(rank(112) > rank(116));
+        assert!(rank(125) > rank(120));
+        assert!(rank(127) > rank(126));
     }
 }
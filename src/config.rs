@@ -0,0 +1,207 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{logger::Logger, muxer::MuxerKind};
+
+pub const CONFIG_PATH: &str = "./config.json";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct Config {
+    #[serde(alias = "SESSDATA")]
+    pub sess_data: String,
+    #[serde(default)]
+    pub bili_jct: String,
+    #[serde(default)]
+    pub refresh_token: String,
+    /// Overridden by `--muxer`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub muxer: Option<MuxerKind>,
+    /// Path of ffmpeg, overridden by `--ffmpeg`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ffmpeg: Option<PathBuf>,
+    /// Extra arguments added to the ffmpeg command line before the output file.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ffmpeg_extra_args: Vec<String>,
+    /// Where intermediate files are kept, overridden by `--work-dir`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub work_dir: Option<PathBuf>,
+}
+
+pub fn read_config(path: &str, logger: &Logger) -> Config {
+    match fs::read_to_string(path) {
+        Ok(contents) => match serde_json::from_str::<Config>(&contents) {
+            Ok(config) => {
+                logger.debug(&format!(
+                    "sess_data parsed as '{}' from '{path}'",
+                    config.sess_data
+                ));
+                config
+            }
+            Err(_) => {
+                logger.warn("配置文件格式不正确，无法下载高清视频");
+                Config::default()
+            }
+        },
+        Err(_) => {
+            logger.warn(&format!("找不到配置文件 '{path}', 无法下载高清视频"));
+            Config::default()
+        }
+    }
+}
+
+/// Writes the config, which holds the login cookies, readable only by the user on unix.
+pub fn write_config(path: &str, config: &Config) -> Result<()> {
+    let contents = serde_json::to_string_pretty(config)?;
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // the mode only applies to new files
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tempdir::TempDir;
+
+    use super::{read_config, write_config, Config};
+    use crate::{logger::Logger, muxer::MuxerKind};
+
+    #[test]
+    fn read_config_config_not_exist() {
+        let temp_dir = TempDir::new("read_config").unwrap();
+        let temp_file = temp_dir
+            .path()
+            .join("non_existing")
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let logger = Logger::new(0);
+        let config = read_config(&temp_file, &logger);
+        assert_eq!(
+            config,
+            Config {
+                sess_data: "".to_owned(),
+                ..Default::default()
+            },
+            "sess_data should be parsed to '' if no config is presented"
+        );
+    }
+
+    #[test]
+    fn read_config_missing_sess_data() {
+        let temp_dir = TempDir::new("read_config").unwrap();
+        let temp_file = temp_dir
+            .path()
+            .join("missing_sess_data")
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let config_content = "{}";
+        fs::write(&temp_file, config_content).expect("Unable to write file");
+        let logger = Logger::new(0);
+        let config = read_config(&temp_file, &logger);
+        assert_eq!(
+            config,
+            Config {
+                sess_data: "".to_owned(),
+                ..Default::default()
+            },
+            "sess_data should be parsed to '' if config does not contain SESSDATA"
+        );
+    }
+
+    #[test]
+    fn read_config_wrong_sess_type() {
+        let temp_dir = TempDir::new("read_config").unwrap();
+        let temp_file = temp_dir
+            .path()
+            .join("wrong_sess_type")
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let config_content = "{ \"SESSDATA\": 2 }";
+        fs::write(&temp_file, config_content).expect("Unable to write file");
+        let logger = Logger::new(0);
+        let config = read_config(&temp_file, &logger);
+        assert_eq!(
+            config,
+            Config {
+                sess_data: "".to_owned(),
+                ..Default::default()
+            },
+            "sess_data should be parsed to '' if SESSDATA is not a string"
+        );
+    }
+
+    #[test]
+    fn read_config_success() {
+        let temp_dir = TempDir::new("read_config").unwrap();
+        let temp_file = temp_dir.path().join("success").to_str().unwrap().to_owned();
+        let config_content = "{ \"SESSDATA\": \"2\" }";
+        fs::write(&temp_file, config_content).expect("Unable to write file");
+        let logger = Logger::new(0);
+        let config = read_config(&temp_file, &logger);
+        assert_eq!(
+            config,
+            Config {
+                sess_data: "2".to_owned(),
+                ..Default::default()
+            },
+            "sess_data should be parsed correctly"
+        );
+    }
+
+    #[test]
+    fn write_config_round_trip() {
+        let temp_dir = TempDir::new("write_config").unwrap();
+        let temp_file = temp_dir
+            .path()
+            .join("round_trip")
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let config = Config {
+            sess_data: "sess".to_owned(),
+            bili_jct: "jct".to_owned(),
+            refresh_token: "token".to_owned(),
+            muxer: Some(MuxerKind::Mkvmerge),
+            ffmpeg: Some(PathBuf::from("/usr/local/bin/ffmpeg")),
+            ffmpeg_extra_args: vec!["-movflags".to_owned(), "+faststart".to_owned()],
+            work_dir: Some(PathBuf::from("/dev/shm/bilibili-downloader")),
+        };
+        write_config(&temp_file, &config).unwrap();
+        let logger = Logger::new(0);
+        assert_eq!(
+            read_config(&temp_file, &logger),
+            config,
+            "written config should be read back unchanged"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_config_only_for_the_user() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new("write_config").unwrap();
+        let temp_file = temp_dir.path().join("config.json");
+        fs::write(&temp_file, "{}").unwrap();
+        fs::set_permissions(&temp_file, fs::Permissions::from_mode(0o644)).unwrap();
+        write_config(temp_file.to_str().unwrap(), &Config::default()).unwrap();
+        let mode = fs::metadata(&temp_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
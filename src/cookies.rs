@@ -0,0 +1,260 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+use rusqlite::{Connection, OpenFlags};
+
+const COOKIE_DOMAIN: &str = "bilibili.com";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    /// A leading `.` means the cookie is sent to subdomains as well.
+    pub domain: String,
+    pub path: String,
+    pub secure: bool,
+    /// Unix timestamp in seconds, 0 for session cookies.
+    pub expires: i64,
+    pub name: String,
+    pub value: String,
+}
+
+/// The path-match rule of RFC 6265, `/foo` matches `/foo` and `/foo/bar` but not `/foobar`.
+fn path_matches(cookie_path: &str, request_path: &str) -> bool {
+    match request_path.strip_prefix(cookie_path) {
+        Some(rest) => rest.is_empty() || cookie_path.ends_with('/') || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+impl Cookie {
+    fn matches(&self, url: &Url, now: i64) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let domain = self.domain.trim_start_matches('.');
+        let domain_matches = host == domain
+            || (self.domain.starts_with('.') && host.ends_with(&format!(".{domain}")));
+        domain_matches
+            && path_matches(&self.path, url.path())
+            && (!self.secure || url.scheme() == "https")
+            && (self.expires == 0 || self.expires > now)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn is_bilibili_domain(domain: &str) -> bool {
+    let domain = domain.trim_start_matches('.');
+    domain == COOKIE_DOMAIN || domain.ends_with(&format!(".{COOKIE_DOMAIN}"))
+}
+
+impl CookieJar {
+    pub fn from_sess_data(sess_data: &str) -> Self {
+        let mut jar = CookieJar::default();
+        if !sess_data.is_empty() {
+            jar.set("SESSDATA", sess_data);
+        }
+        jar
+    }
+
+    /// Parses a Netscape cookies.txt, only cookies for bilibili.com are kept.
+    pub fn parse_netscape(contents: &str) -> Self {
+        let mut jar = CookieJar::default();
+        for line in contents.lines() {
+            let line = match line.strip_prefix("#HttpOnly_") {
+                Some(line) => line,
+                None if line.starts_with('#') => continue,
+                None => line,
+            };
+            let fields: Vec<&str> = line.trim_end_matches('\r').split('\t').collect();
+            let [domain, include_subdomains, path, secure, expires, name, value] = fields[..]
+            else {
+                continue;
+            };
+            let domain = if include_subdomains == "TRUE" && !domain.starts_with('.') {
+                format!(".{domain}")
+            } else {
+                domain.to_owned()
+            };
+            jar.insert(Cookie {
+                domain,
+                path: path.to_owned(),
+                secure: secure == "TRUE",
+                expires: expires.parse().unwrap_or(0),
+                name: name.to_owned(),
+                value: value.to_owned(),
+            });
+        }
+        jar
+    }
+
+    pub fn read_netscape(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read cookies from '{}': {e}", path.display()))?;
+        Ok(Self::parse_netscape(&contents))
+    }
+
+    /// Reads cookies from a Firefox `cookies.sqlite`, `path` can also be the profile directory.
+    pub fn read_firefox(path: &Path) -> Result<Self> {
+        let database = if path.is_dir() {
+            path.join("cookies.sqlite")
+        } else {
+            path.to_path_buf()
+        };
+        if !database.is_file() {
+            return Err(anyhow!(
+                "firefox cookie database '{}' not found",
+                database.display()
+            ));
+        }
+        // firefox keeps the database locked while running, so read from a copy instead
+        let copy_dir = tempfile::Builder::new()
+            .prefix("bilibili-downloader-cookies")
+            .tempdir()?;
+        let copy = copy_dir.path().join("cookies.sqlite");
+        fs::copy(&database, &copy)?;
+        let wal = PathBuf::from(format!("{}-wal", database.display()));
+        if wal.is_file() {
+            fs::copy(&wal, copy_dir.path().join("cookies.sqlite-wal"))?;
+        }
+        // the copy is removed with `copy_dir`
+        Self::query_firefox(&copy)
+    }
+
+    fn query_firefox(database: &Path) -> Result<Self> {
+        let connection = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        let mut statement = connection
+            .prepare("SELECT host, path, isSecure, expiry, name, value FROM moz_cookies")?;
+        let rows = statement.query_map([], |row| {
+            Ok(Cookie {
+                domain: row.get(0)?,
+                path: row.get(1)?,
+                secure: row.get::<_, i64>(2)? != 0,
+                expires: row.get(3)?,
+                name: row.get(4)?,
+                value: row.get(5)?,
+            })
+        })?;
+        let mut jar = CookieJar::default();
+        for cookie in rows {
+            jar.insert(cookie?);
+        }
+        Ok(jar)
+    }
+
+    fn insert(&mut self, cookie: Cookie) {
+        if !is_bilibili_domain(&cookie.domain) {
+            return;
+        }
+        self.cookies.retain(|c| {
+            !(c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path)
+        });
+        self.cookies.push(cookie);
+    }
+
+    /// Sets a session cookie for `.bilibili.com`.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.insert(Cookie {
+            domain: format!(".{COOKIE_DOMAIN}"),
+            path: "/".to_owned(),
+            secure: false,
+            expires: 0,
+            name: name.to_owned(),
+            value: value.to_owned(),
+        });
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.cookies
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| c.value.as_str())
+    }
+
+    /// Builds the `cookie` header to be sent to `url`.
+    pub fn header_for(&self, url: &str) -> Option<String> {
+        let url = Url::parse(url).ok()?;
+        let now = now();
+        let pairs: Vec<String> = self
+            .cookies
+            .iter()
+            .filter(|c| c.matches(&url, now))
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect();
+        if pairs.is_empty() {
+            None
+        } else {
+            Some(pairs.join("; "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CookieJar;
+
+    const COOKIES_TXT: &str = "# Netscape HTTP Cookie File
+.bilibili.com\tTRUE\t/\tFALSE\t0\tbuvid3\tbuvid
+#HttpOnly_.bilibili.com\tTRUE\t/\tTRUE\t0\tSESSDATA\tsess
+passport.bilibili.com\tFALSE\t/x/\tFALSE\t0\tpassport_only\tyes
+.bilibili.com\tTRUE\t/\tFALSE\t1\texpired\tyes
+.example.com\tTRUE\t/\tFALSE\t0\tother\tyes
+";
+
+    #[test]
+    fn parse_netscape_keeps_bilibili_cookies() {
+        let jar = CookieJar::parse_netscape(COOKIES_TXT);
+        assert_eq!(jar.get("SESSDATA"), Some("sess"), "HttpOnly cookie is kept");
+        assert_eq!(jar.get("other"), None, "other domains are dropped");
+    }
+
+    #[test]
+    fn header_for_matches_host_path_and_scheme() {
+        let jar = CookieJar::parse_netscape(COOKIES_TXT);
+        assert_eq!(
+            jar.header_for("https://www.bilibili.com/video/BV1")
+                .as_deref(),
+            Some("buvid3=buvid; SESSDATA=sess"),
+        );
+        assert_eq!(
+            jar.header_for("http://api.bilibili.com/x").as_deref(),
+            Some("buvid3=buvid"),
+            "secure cookie should not be sent over http"
+        );
+        assert_eq!(
+            jar.header_for("https://passport.bilibili.com/x/passport-login")
+                .as_deref(),
+            Some("buvid3=buvid; SESSDATA=sess; passport_only=yes"),
+        );
+        assert_eq!(jar.header_for("https://example.com/"), None);
+    }
+
+    #[test]
+    fn header_for_matches_whole_path_segments() {
+        let jar =
+            CookieJar::parse_netscape(".bilibili.com\tTRUE\t/video\tFALSE\t0\tvideo_only\tyes");
+        let video_only = |url: &str| {
+            jar.header_for(url)
+                .unwrap_or_default()
+                .contains("video_only=yes")
+        };
+        assert!(video_only("https://www.bilibili.com/video"));
+        assert!(video_only("https://www.bilibili.com/video/BV1"));
+        assert!(!video_only("https://www.bilibili.com/videos"));
+        assert!(!video_only("https://www.bilibili.com/"));
+    }
+}
@@ -1,59 +1,115 @@
-use crate::logger::Logger;
+use crate::{cookies::CookieJar, logger::Logger};
 
 use anyhow::Result;
 use async_trait::async_trait;
 use flate2::read::GzDecoder;
-use reqwest::StatusCode;
+use reqwest::{header::SET_COOKIE, RequestBuilder, StatusCode};
 use std::{
+    fmt::{self, Display},
     fs,
+    future::Future,
     io::{Read, Write},
-    path::PathBuf,
+    path::Path,
+    time::Duration,
 };
 
 #[cfg(test)]
 use mockall::automock;
 
+const RISK_CONTROL_RETRIES: u32 = 3;
+const RISK_CONTROL_COOLDOWN: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// HTTP 412, the request is blocked by the risk control of bilibili.
+    RiskControl {
+        url: String,
+    },
+    Status {
+        url: String,
+        status: StatusCode,
+    },
+}
+
+impl Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::RiskControl { url } => {
+                write!(f, "request to '{url}' is blocked by risk control (412)")
+            }
+            FetchError::Status { url, status } => write!(f, "non 200 status: '{url}': {status}"),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+pub struct FetchResponse {
+    pub body: Vec<u8>,
+    /// `name=value` pairs taken from the `Set-Cookie` headers.
+    pub cookies: Vec<(String, String)>,
+}
+
 #[cfg_attr(test, automock)]
 #[async_trait(?Send)]
 pub trait Fetching {
-    async fn fetch_body(&self, url: &String) -> Result<Vec<u8>>;
-    async fn download_to(&self, url: &String, output: &PathBuf) -> Result<()>;
+    async fn fetch_body(&self, url: &str) -> Result<Vec<u8>>;
+    async fn fetch_with_cookies(&self, url: &str) -> Result<FetchResponse>;
+    async fn post_form(&self, url: &str, form: &[(String, String)]) -> Result<FetchResponse>;
+    async fn download_to(&self, url: &str, output: &Path) -> Result<()>;
 }
 
 pub struct Crawler<'a> {
-    sess_data: String,
+    cookies: CookieJar,
     logger: &'a Logger,
 }
 
 impl<'a> Crawler<'a> {
-    pub fn new(sess_data: &str, logger: &'a Logger) -> Self {
-        Crawler {
-            sess_data: String::from(sess_data),
-            logger,
-        }
+    pub fn new(cookies: CookieJar, logger: &'a Logger) -> Self {
+        Crawler { cookies, logger }
     }
-}
 
-#[async_trait(?Send)]
-impl<'a> Fetching for Crawler<'a> {
-    async fn fetch_body(&self, url: &String) -> Result<Vec<u8>> {
-        let mut cookie = "CURRENT_QUALITY=32; ".to_owned();
-        if self.sess_data != "" {
-            cookie.push_str(&format!("SESSDATA={}", self.sess_data));
-        }
-        let response = reqwest::Client::new().get(url)
+    async fn send(&self, url: &str, request: RequestBuilder) -> Result<FetchResponse> {
+        retry_on_risk_control(self.logger, RISK_CONTROL_COOLDOWN, || {
+            let attempt = request
+                .try_clone()
+                .expect("requests without streaming body can be cloned");
+            self.send_once(url, attempt)
+        })
+        .await
+    }
+
+    async fn send_once(&self, url: &str, request: RequestBuilder) -> Result<FetchResponse> {
+        let mut request = request
             .header("user-agent", "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/98.0.4758.80 Safari/537.36")
-            .header("referer", "https://www.bilibili.com")
-            .header("cookie", cookie)
-            .send()
-            .await?;
+            .header("referer", "https://www.bilibili.com");
+        if let Some(cookie) = self.cookies.header_for(url) {
+            request = request.header("cookie", cookie);
+        }
+        let response = request.send().await?;
         let status = response.status();
-        if status != StatusCode::OK {
-            panic!("non 200 status: '{url}': {status}");
+        if status == StatusCode::PRECONDITION_FAILED {
+            return Err(FetchError::RiskControl {
+                url: url.to_owned(),
+            }
+            .into());
+        } else if status != StatusCode::OK {
+            return Err(FetchError::Status {
+                url: url.to_owned(),
+                status,
+            }
+            .into());
         } else {
             self.logger
                 .verbose(&format!("status for '{url}': {status}"));
         }
+        let cookies = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(parse_set_cookie)
+            .collect();
         let encoding = match response.headers().get("Content-Encoding") {
             Some(v) => v.to_str()?.to_owned(),
             None => String::from(""),
@@ -61,17 +117,70 @@ impl<'a> Fetching for Crawler<'a> {
         self.logger
             .verbose(&format!("encoding is '{encoding}' for '{url}'"));
         let body_bytes = response.bytes().await?;
-        if encoding == "gzip" {
+        let body = if encoding == "gzip" {
             let mut reader = GzDecoder::new(&body_bytes[..]);
             let mut buf: Vec<u8> = Vec::new();
             reader.read_to_end(&mut buf)?;
-            Ok(buf)
+            buf
         } else {
-            Ok(Vec::from(&body_bytes[..]))
+            Vec::from(&body_bytes[..])
+        };
+        Ok(FetchResponse { body, cookies })
+    }
+}
+
+/// Retries `attempt` if it is blocked by risk control, waiting `cooldown` before the first retry
+/// and twice as long before each following one.
+async fn retry_on_risk_control<T, Fut>(
+    logger: &Logger,
+    cooldown: Duration,
+    mut attempt: impl FnMut() -> Fut,
+) -> Result<T>
+where
+    Fut: Future<Output = Result<T>>,
+{
+    let mut retries = 0;
+    loop {
+        match attempt().await {
+            Err(e)
+                if retries < RISK_CONTROL_RETRIES
+                    && matches!(e.downcast_ref(), Some(FetchError::RiskControl { .. })) =>
+            {
+                retries += 1;
+                let cooldown = cooldown * 2u32.pow(retries - 1);
+                logger.warn(&format!(
+                    "请求被风控拦截 (412)，{} 秒后重试 ({retries}/{RISK_CONTROL_RETRIES})",
+                    cooldown.as_secs()
+                ));
+                tokio::time::sleep(cooldown).await;
+            }
+            result => return result,
         }
     }
+}
+
+fn parse_set_cookie(header: &str) -> Option<(String, String)> {
+    let pair = header.split(';').next()?;
+    let (name, value) = pair.split_once('=')?;
+    Some((name.trim().to_owned(), value.trim().to_owned()))
+}
+
+#[async_trait(?Send)]
+impl<'a> Fetching for Crawler<'a> {
+    async fn fetch_body(&self, url: &str) -> Result<Vec<u8>> {
+        Ok(self.fetch_with_cookies(url).await?.body)
+    }
+
+    async fn fetch_with_cookies(&self, url: &str) -> Result<FetchResponse> {
+        self.send(url, reqwest::Client::new().get(url)).await
+    }
+
+    async fn post_form(&self, url: &str, form: &[(String, String)]) -> Result<FetchResponse> {
+        self.send(url, reqwest::Client::new().post(url).form(form))
+            .await
+    }
 
-    async fn download_to(&self, url: &String, output: &PathBuf) -> Result<()> {
+    async fn download_to(&self, url: &str, output: &Path) -> Result<()> {
         if let Some(output_dir) = output.parent() {
             fs::create_dir_all(output_dir)?;
         };
@@ -84,3 +193,85 @@ impl<'a> Fetching for Crawler<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, time::Duration};
+
+    use anyhow::anyhow;
+    use reqwest::StatusCode;
+    use tokio::time::Instant;
+
+    use super::{retry_on_risk_control, FetchError, Fetching, MockFetching};
+    use crate::logger::Logger;
+
+    const URL: &str = "https://api.bilibili.com/x/web-interface/nav";
+
+    fn risk_control() -> anyhow::Error {
+        FetchError::RiskControl {
+            url: URL.to_owned(),
+        }
+        .into()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_risk_control_with_exponential_cooldown() {
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .times(4)
+            .returning(|_| Err(risk_control()));
+        let logger = Logger::new(0);
+        let start = Instant::now();
+        let error =
+            retry_on_risk_control(&logger, Duration::from_secs(1), || crawler.fetch_body(URL))
+                .await
+                .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(FetchError::RiskControl { .. })
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(1 + 2 + 4));
+    }
+
+    #[tokio::test]
+    async fn retry_risk_control_until_success() {
+        let mut crawler = MockFetching::new();
+        let calls = Cell::new(0);
+        crawler.expect_fetch_body().times(3).returning(move |_| {
+            calls.set(calls.get() + 1);
+            if calls.get() < 3 {
+                Err(risk_control())
+            } else {
+                Ok(b"ok".to_vec())
+            }
+        });
+        let logger = Logger::new(0);
+        let body = retry_on_risk_control(&logger, Duration::ZERO, || crawler.fetch_body(URL))
+            .await
+            .unwrap();
+        assert_eq!(body, b"ok");
+    }
+
+    #[tokio::test]
+    async fn no_retry_for_other_errors() {
+        let mut crawler = MockFetching::new();
+        crawler.expect_fetch_body().times(1).returning(|_| {
+            Err(FetchError::Status {
+                url: URL.to_owned(),
+                status: StatusCode::NOT_FOUND,
+            }
+            .into())
+        });
+        let logger = Logger::new(0);
+        let result = retry_on_risk_control(&logger, Duration::ZERO, || crawler.fetch_body(URL));
+        assert!(result.await.is_err());
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .times(1)
+            .returning(|_| Err(anyhow!("connection reset")));
+        let result = retry_on_risk_control(&logger, Duration::ZERO, || crawler.fetch_body(URL));
+        assert!(result.await.is_err());
+    }
+}
@@ -1,122 +1,1141 @@
-use std::{fs, path::PathBuf, process::Command};
+use std::{
+    cell::RefCell,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{anyhow, Result};
 use scraper::Html;
 
 use crate::{
-    bilibili::{extract_initial_state, extract_title, fetch_video_info},
+    archive::{Archive, ArchiveEntry},
+    bilibili::{
+        extract_play_info, extract_title, extract_video_metadata, fetch_video_info,
+        required_access, Access, Account, Resource, VideoInfo, VideoMetadata, VideoPreferences,
+    },
     crawler::Fetching,
+    format_selector::codec_family,
+    format_selector::FormatSelector,
+    formats::FormatList,
     logger::Logger,
+    muxer::{Container, Muxer},
+    output::{OutputTemplate, TemplateValues},
+    sanitize::Sanitizer,
+    workdir::{persist, WorkDir},
 };
 
+struct VideoPage {
+    html: Html,
+    metadata: VideoMetadata,
+    title: String,
+}
+
+struct ResolvedVideo {
+    metadata: VideoMetadata,
+    title: String,
+    video_info: VideoInfo,
+}
+
 struct VideoSource {
     title: String,
-    video_url: String,
-    audio_url: String,
+    /// Names the work directory, unique per video part.
+    key: String,
+    /// The merged file, or the video stream with `no_merge`.
+    output_path: PathBuf,
+    /// Where the streams are kept with `no_merge` or `keep_streams`, `None` if not kept or
+    /// skipped as the file exists.
+    video_stream_path: Option<PathBuf>,
+    audio_stream_path: Option<PathBuf>,
+    container: Container,
+    video: Resource,
+    audio: Resource,
+}
+
+/// E.g. `1080p60.avc.mp4`, the fps is only added if it is higher than 30.
+fn video_suffix(video: &Resource) -> String {
+    let fps = video.frame_rate.round() as u32;
+    let fps = if fps > 30 {
+        fps.to_string()
+    } else {
+        String::new()
+    };
+    format!("{}p{fps}.{}.mp4", video.height, codec_family(&video.codecs))
+}
+
+/// E.g. `128k.aac.m4a`. The streams are fragmented MP4 whatever the codec, so FLAC and E-AC-3
+/// are saved as e.g. `1411k.flac.m4a`.
+fn audio_suffix(audio: &Resource) -> String {
+    format!(
+        "{}k.{}.m4a",
+        audio.bandwidth / 1000,
+        codec_family(&audio.codecs)
+    )
+}
+
+/// What to do if only a trial clip is available, e.g. VIP-only content for non-VIP accounts.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum PreviewAction {
+    Fail,
+    /// Saves the clip with a `.preview` marker in the file name.
+    Save,
+}
+
+/// What to do if the output file already exists.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OnExists {
+    /// Skips the video. The video page is fetched first to render the file name, unless the
+    /// output template only uses `{bvid}` and `{page}`. The login and fingerprint requests at
+    /// startup are made either way.
+    Skip,
+    Overwrite,
+    /// Saves as `name (1).mp4`, `name (2).mp4`...
+    Rename,
+}
+
+pub struct DownloadOptions {
+    /// The highest quality id to download, the best available one is used if not set.
+    pub quality: Option<u8>,
+    /// Overrides `quality` if set.
+    pub format: Option<FormatSelector>,
+    pub preferences: VideoPreferences,
+    pub on_preview: PreviewAction,
+    pub output_dir: PathBuf,
+    pub output: OutputTemplate,
+    pub sanitizer: Sanitizer,
+    /// Intermediate files are kept in a subdirectory per video part.
+    pub work_dir: PathBuf,
+    pub on_exists: OnExists,
+    pub archive: Option<RefCell<Archive>>,
+    /// Downloads archived videos again if a higher quality is available.
+    pub upgrade: bool,
+    /// Chosen by the codecs if not set.
+    pub container: Option<Container>,
+    /// Keeps the video and audio streams as separate files without merging.
+    pub no_merge: bool,
+    /// Keeps the video and audio streams next to the merged file.
+    pub keep_streams: bool,
+    pub account: Account,
 }
 
 pub struct Downloader<'a, F: Fetching> {
     logger: &'a Logger,
     crawler: &'a F,
+    muxer: &'a dyn Muxer,
+    options: DownloadOptions,
 }
 
 impl<'a, F: Fetching> Downloader<'a, F> {
-    pub fn new(logger: &'a Logger, crawler: &'a F) -> Self {
-        Downloader { logger, crawler }
+    pub fn new(
+        logger: &'a Logger,
+        crawler: &'a F,
+        muxer: &'a dyn Muxer,
+        options: DownloadOptions,
+    ) -> Self {
+        Downloader {
+            logger,
+            crawler,
+            muxer,
+            options,
+        }
+    }
+
+    fn warn_access(&self, video_info: &VideoInfo, quality: u8) {
+        let quality_name = video_info.get_quality_name(quality);
+        let access = required_access(quality);
+        if access <= self.options.account.access() {
+            return;
+        }
+        match access {
+            Access::Vip => self.logger.warn(&format!(
+                "清晰度 '{quality_name}' 需要大会员，当前账号不是大会员"
+            )),
+            Access::Login => self
+                .logger
+                .warn(&format!("清晰度 '{quality_name}' 需要登录，当前未登录")),
+            Access::Anonymous => {}
+        }
     }
 
     async fn fetch_html_body(&self, video_id: &str) -> Result<Html> {
-        let url = format!("https://www.bilibili.com/video/{video_id}/");
+        // e.g. `BV1xx411c7mD?p=2` for the second part of a multi-part video
+        let (id, query) = video_id.split_once('?').unwrap_or((video_id, ""));
+        let url = if id.starts_with("ep") || id.starts_with("ss") {
+            format!("https://www.bilibili.com/bangumi/play/{id}")
+        } else {
+            format!("https://www.bilibili.com/video/{id}/")
+        };
+        let url = if query.is_empty() {
+            url
+        } else {
+            format!("{url}?{query}")
+        };
         let bytes = self.crawler.fetch_body(&url).await?;
         let str = std::str::from_utf8(&bytes)?;
         Ok(Html::parse_document(str))
     }
 
-    fn merge_video_and_audio(
-        &self,
-        video_path: &PathBuf,
-        audio_path: &PathBuf,
-        output_path: &PathBuf,
-    ) -> Result<()> {
-        let output = Command::new("ffmpeg")
-            .arg("-i")
-            .arg(&video_path)
-            .arg("-i")
-            .arg(&audio_path)
-            .arg("-c:v")
-            .arg("copy")
-            .arg("-c:a")
-            .arg("aac")
-            .arg(output_path)
-            .output()
-            .expect("合并视频音频失败");
-
-        let Some(exit_code) = output.status.code() else {
-            return Err(anyhow!(
-                "ffmpeg exit without exit code, stdout: {:}, stderr: {:}",
-                String::from_utf8(output.stdout).unwrap(),
-                String::from_utf8(output.stderr).unwrap()
-            ));
+    async fn download_and_merge(&self, source: &VideoSource) -> Result<()> {
+        let output_path = &source.output_path;
+        // removed when dropped, also on errors
+        let work_dir = WorkDir::create(&self.options.work_dir, &source.key, self.logger)?;
+        let video_path = work_dir.join("video.mp4");
+        let audio_path = work_dir.join("audio.mp4");
+        let merged_path = work_dir.join(&format!("merged.{}", source.container.extension()));
+        fs::create_dir_all(output_path.parent().unwrap())?;
+
+        tokio::try_join!(
+            self.crawler
+                .download_to(&source.video.base_url, &video_path),
+            self.crawler
+                .download_to(&source.audio.base_url, &audio_path),
+        )?;
+        if !self.options.no_merge {
+            self.muxer
+                .mux(&video_path, &audio_path, &merged_path, source.container)?;
+            persist(&merged_path, output_path)?;
+        }
+        for (from, to) in [
+            (&video_path, &source.video_stream_path),
+            (&audio_path, &source.audio_stream_path),
+        ] {
+            if let Some(to) = to {
+                persist(from, to)?;
+                self.logger.info(&format!("已保存原始流: {}", to.display()));
+            }
+        }
+        self.logger.info(&format!(
+            "{} 下载完成: {}",
+            source.title,
+            output_path.display()
+        ));
+        Ok(())
+    }
+
+    /// The container of the output, MKV if the audio does not fit in the requested container.
+    /// `audio` is `None` before the streams are selected, AAC is assumed then.
+    fn container(&self, audio: Option<&Resource>) -> Result<Container> {
+        let is_flac = audio.is_some_and(|a| codec_family(&a.codecs) == "flac");
+        self.container_for(is_flac, audio.is_some())
+    }
+
+    /// Errors if the muxer cannot write FLAC audio into any container it supports.
+    fn container_for(&self, is_flac: bool, warn: bool) -> Result<Container> {
+        let capabilities = self.muxer.capabilities();
+        let container = match self.options.container {
+            Some(Container::Mp4) if is_flac && !capabilities.flac_in_mp4 => {
+                if warn {
+                    self.logger.warn("MP4 无法容纳 FLAC 音频，改用 MKV");
+                }
+                Container::Mkv
+            }
+            Some(Container::Mov) if is_flac => {
+                if warn {
+                    self.logger.warn("MOV 无法容纳 FLAC 音频，改用 MKV");
+                }
+                Container::Mkv
+            }
+            Some(container) => container,
+            None if is_flac => Container::Mkv,
+            None => Container::Mp4,
+        };
+        if capabilities.containers.contains(&container) {
+            return Ok(container);
+        }
+        let fallback = capabilities
+            .containers
+            .first()
+            .copied()
+            .unwrap_or(Container::Mp4);
+        let fits_flac = match fallback {
+            Container::Mkv => true,
+            Container::Mp4 => capabilities.flac_in_mp4,
+            Container::Mov => false,
         };
-        if exit_code != 0 {
+        if is_flac && !fits_flac {
             return Err(anyhow!(
-                "ffmpeg exit code: {}, stdout: {:}, stderr: {:}",
-                exit_code,
-                String::from_utf8(output.stdout).unwrap(),
-                String::from_utf8(output.stderr).unwrap()
+                "muxer {} cannot write FLAC audio into {}, use --muxer ffmpeg or mkvmerge, \
+                 or select another audio with --format",
+                self.muxer.name(),
+                fallback.extension()
             ));
         }
-        self.logger.verbose(&format!(
-            "ffmpeg stdout: {:}",
-            String::from_utf8(output.stdout).unwrap()
-        ));
-        self.logger.verbose(&format!(
-            "ffmpeg stderr: {:}",
-            String::from_utf8(output.stderr).unwrap()
-        ));
-        Ok(())
+        if warn && self.options.container.is_some() {
+            self.logger.warn(&format!(
+                "{} 不支持 {}，改用 {}",
+                self.muxer.name(),
+                container.extension(),
+                fallback.extension()
+            ));
+        }
+        Ok(fallback)
     }
 
-    async fn download_and_merge(&self, source: &VideoSource) -> Result<()> {
-        let title = source.title.replace("/", "|");
-        let base_dir = PathBuf::from(".");
-        let video_path = base_dir
-            .join("download")
-            .join(format!("{}_video.mp4", title));
-        let audio_path = base_dir
-            .join("download")
-            .join(format!("{}_audio.mp4", title));
-        let output_path = base_dir.join("download").join(format!("{}.mp4", title));
-        fs::create_dir_all(output_path.parent().unwrap())?;
+    /// The directory and the file stem rendered from the output template.
+    fn rendered_path(&self, values: &TemplateValues) -> (PathBuf, String) {
+        let (dir, stem) = self.options.output.render(values, &self.options.sanitizer);
+        (self.options.output_dir.join(dir), stem)
+    }
 
-        tokio::try_join!(
-            self.crawler.download_to(&source.video_url, &video_path),
-            self.crawler.download_to(&source.audio_url, &audio_path),
-        )?;
-        self.merge_video_and_audio(&video_path, &audio_path, &output_path)?;
-        self.logger.info(&format!("{title} 下载完成"));
-        fs::remove_file(video_path)?;
-        fs::remove_file(audio_path)?;
-        Ok(())
+    /// Returns the output path following the `on_exists` policy, `None` to skip the video.
+    fn output_path(&self, values: &TemplateValues, extension: &str) -> Option<PathBuf> {
+        let (dir, stem) = self.rendered_path(values);
+        self.apply_on_exists(&dir, &stem, extension)
     }
 
-    pub async fn download(&self, video_id: &str) -> Result<()> {
+    /// Returns the path of a kept stream next to `output_path`, whose suffix after the stem is
+    /// `extension`. Files of the `archived` download are replaced, otherwise the `on_exists`
+    /// policy is followed, `None` to skip saving the stream.
+    fn stream_path(
+        &self,
+        output_path: &Path,
+        extension: &str,
+        suffix: &str,
+        archived: Option<&ArchiveEntry>,
+    ) -> Option<PathBuf> {
+        let file_name = output_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let stem = file_name
+            .strip_suffix(&format!(".{extension}"))
+            .unwrap_or(&file_name);
+        let dir = output_path.parent()?;
+        let path = dir.join(
+            self.options
+                .sanitizer
+                .file_name(stem, &format!(".{suffix}")),
+        );
+        if archived.is_some_and(|entry| entry.paths().any(|p| *p == path)) {
+            return Some(path);
+        }
+        self.apply_on_exists(dir, stem, suffix)
+    }
+
+    /// Applies the `on_exists` policy to `dir/stem.extension`, `None` to skip it.
+    fn apply_on_exists(&self, dir: &Path, stem: &str, extension: &str) -> Option<PathBuf> {
+        let sanitizer = &self.options.sanitizer;
+        let path = dir.join(sanitizer.file_name(stem, &format!(".{extension}")));
+        match self.options.on_exists {
+            OnExists::Skip if path.exists() => {
+                self.logger
+                    .info(&format!("{} 已存在，跳过", path.display()));
+                None
+            }
+            OnExists::Rename => Some(sanitizer.unique_path(dir, stem, extension)),
+            _ => Some(path),
+        }
+    }
+
+    /// Checks whether the output exists without any request, only possible if the output
+    /// template only uses fields that can be read from the video id.
+    fn exists_offline(&self, video_id: &str) -> bool {
+        if self.options.on_exists != OnExists::Skip
+            || self.options.upgrade
+            // the file name depends on the streams
+            || self.options.no_merge
+            || !self.options.output.is_offline()
+        {
+            return false;
+        }
+        let (id, query) = video_id.split_once('?').unwrap_or((video_id, ""));
+        if !id.starts_with("BV") {
+            return false;
+        }
+        let page = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("p="))
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(1);
+        let metadata = VideoMetadata {
+            bvid: id.to_owned(),
+            page,
+            ..Default::default()
+        };
+        let values = TemplateValues {
+            metadata: &metadata,
+            title: "",
+            quality: "",
+        };
+        let Ok(container) = self.container(None) else {
+            return false;
+        };
+        // only a format selector picks FLAC audio, which may be saved in another container
+        if self.options.format.is_some() && self.container_for(true, false).ok() != Some(container)
+        {
+            return false;
+        }
+        self.output_path(&values, container.extension()).is_none()
+    }
+
+    fn archived(&self, metadata: &VideoMetadata) -> Option<ArchiveEntry> {
+        let archive = self.options.archive.as_ref()?.borrow();
+        archive.find(&metadata.bvid, metadata.cid).cloned()
+    }
+
+    async fn fetch_page(&self, video_id: &str) -> Result<VideoPage> {
         let html = self.fetch_html_body(video_id).await?;
-        let title = extract_title(&html, video_id)?;
+        let metadata = extract_video_metadata(&html)?;
+        let title = extract_title(&html, &metadata, video_id)?;
         self.logger.info(&format!("title found as '{title}'"));
-        let initial_state = extract_initial_state(&html)?;
-        let video_info =
-            fetch_video_info(self.crawler, &initial_state.bvid, initial_state.cid).await?;
+        Ok(VideoPage {
+            html,
+            metadata,
+            title,
+        })
+    }
+
+    /// Reads the streams from the page, falls back to the playurl API.
+    async fn fetch_play_info(&self, page: &VideoPage) -> Result<VideoInfo> {
+        match extract_play_info(&page.html) {
+            Ok(Some(video_info)) => {
+                self.logger.debug("use __playinfo__ from the video page");
+                Ok(video_info)
+            }
+            result => {
+                if let Err(e) = result {
+                    self.logger
+                        .debug(&format!("failed to read __playinfo__ from the page: {e}"));
+                }
+                let metadata = &page.metadata;
+                fetch_video_info(self.crawler, &metadata.bvid, metadata.cid).await
+            }
+        }
+    }
+
+    async fn resolve(&self, video_id: &str) -> Result<ResolvedVideo> {
+        let page = self.fetch_page(video_id).await?;
+        let video_info = self.fetch_play_info(&page).await?;
+        Ok(ResolvedVideo {
+            metadata: page.metadata,
+            title: page.title,
+            video_info,
+        })
+    }
+
+    /// Writes the formats to `out`, logs go to stderr so that the JSON can be piped.
+    pub async fn list_formats(
+        &self,
+        video_id: &str,
+        json: bool,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        let resolved = self.resolve(video_id).await?;
+        let formats = FormatList::new(&resolved.metadata, &resolved.title, &resolved.video_info);
+        if json {
+            writeln!(out, "{}", formats.to_json()?)?;
+        } else {
+            writeln!(out, "{}", formats.to_table())?;
+        }
+        Ok(())
+    }
+
+    pub async fn download(&self, video_id: &str) -> Result<()> {
+        if self.exists_offline(video_id) {
+            return Ok(());
+        }
+        let page = self.fetch_page(video_id).await?;
+        // checked before the play info, as the playurl API is the one prone to risk control
+        let archived = self.archived(&page.metadata);
+        if let (Some(entry), false) = (&archived, self.options.upgrade) {
+            self.logger.info(&format!(
+                "'{}' 已在下载记录中 ({})，跳过",
+                page.title,
+                entry.path.display()
+            ));
+            return Ok(());
+        }
+        let video_info = self.fetch_play_info(&page).await?;
+        let VideoPage {
+            metadata, title, ..
+        } = page;
+        let full_duration = metadata.part_duration().max(0) as u64;
+        let is_preview = video_info.is_preview(full_duration);
+        if is_preview {
+            match self.options.on_preview {
+                PreviewAction::Fail => {
+                    return Err(anyhow!(
+                        "only a preview of {}s is available for the {}s video, \
+                         use --on-preview save to keep it",
+                        video_info.duration,
+                        full_duration
+                    ))
+                }
+                PreviewAction::Save => self.logger.warn(&format!(
+                    "'{title}' 只能下载 {} 秒的试看片段，文件名将标记为 .preview",
+                    video_info.duration
+                )),
+            }
+        }
+        let selection = match &self.options.format {
+            Some(format) => format.select(&video_info, &self.options.preferences),
+            None => match self.options.quality {
+                Some(quality) => FormatSelector::with_max_quality(quality),
+                None => FormatSelector::best(),
+            }
+            .select(&video_info, &self.options.preferences),
+        }
+        .ok_or_else(|| anyhow!("no format matches the format selector"))?;
+        let video = selection.video;
+        if self.options.format.is_some() {
+            self.warn_access(&video_info, video.id as u8);
+        } else {
+            let target_quality = self
+                .options
+                .quality
+                .unwrap_or_else(|| video_info.get_highest_quality(&self.options.preferences));
+            self.warn_access(&video_info, target_quality);
+            if video.id < target_quality.into() {
+                self.logger.warn(&format!(
+                    "无法下载清晰度 '{}'，将下载较低的清晰度 '{}'",
+                    video_info.get_quality_name(target_quality),
+                    video_info.get_quality_name(video.id as u8),
+                ));
+            } else if video.id > target_quality.into() {
+                self.logger.warn(&format!(
+                    "没有不高于 '{}' 的清晰度，将下载最低的清晰度 '{}'",
+                    video_info.get_quality_name(target_quality),
+                    video_info.get_quality_name(video.id as u8),
+                ));
+            }
+        }
         self.logger.info(&format!(
             "use quality: {}",
-            video_info.get_hightest_quality_name()
+            video_info.get_quality_name(video.id as u8)
         ));
+        let quality_name = video_info.get_quality_name(video.id as u8);
+        let values = TemplateValues {
+            metadata: &metadata,
+            title: &title,
+            quality: &quality_name,
+        };
+        let container = self.container(Some(&selection.audio))?;
+        let extension = if self.options.no_merge {
+            video_suffix(&video)
+        } else {
+            container.extension().to_owned()
+        };
+        let extension = if is_preview {
+            format!("preview.{extension}")
+        } else {
+            extension
+        };
+        let preferences = &self.options.preferences;
+        let output_path = match &archived {
+            Some(entry)
+                if is_preview
+                    || preferences.quality_rank(video.id)
+                        <= preferences.quality_rank(entry.quality) =>
+            {
+                self.logger.info(&format!(
+                    "'{title}' 没有比已下载的 '{}' 更高的清晰度，跳过",
+                    video_info.get_quality_name(entry.quality as u8)
+                ));
+                return Ok(());
+            }
+            Some(entry) => {
+                self.logger.info(&format!(
+                    "'{title}' 将从 '{}' 升级到 '{quality_name}'",
+                    video_info.get_quality_name(entry.quality as u8)
+                ));
+                let (dir, stem) = self.rendered_path(&values);
+                let path = dir.join(
+                    self.options
+                        .sanitizer
+                        .file_name(&stem, &format!(".{extension}")),
+                );
+                // replace the old file in place if the name is unchanged, otherwise the old file
+                // is removed once the new one is saved
+                if path == entry.path {
+                    Some(path)
+                } else {
+                    self.output_path(&values, &extension)
+                }
+            }
+            None => self.output_path(&values, &extension),
+        };
+        let Some(output_path) = output_path else {
+            return Ok(());
+        };
+        let (video_stream_path, audio_stream_path) = if self.options.no_merge {
+            let audio_suffix = audio_suffix(&selection.audio);
+            (
+                Some(output_path.clone()),
+                self.stream_path(&output_path, &extension, &audio_suffix, archived.as_ref()),
+            )
+        } else if self.options.keep_streams {
+            let video_suffix = video_suffix(&video);
+            let audio_suffix = audio_suffix(&selection.audio);
+            (
+                self.stream_path(&output_path, &extension, &video_suffix, archived.as_ref()),
+                self.stream_path(&output_path, &extension, &audio_suffix, archived.as_ref()),
+            )
+        } else {
+            (None, None)
+        };
         let source = VideoSource {
             title,
-            video_url: video_info.get_best_video().base_url,
-            audio_url: video_info.get_best_audio().base_url,
+            key: format!("{}_{}", metadata.bvid, metadata.cid),
+            output_path,
+            video_stream_path,
+            audio_stream_path,
+            container,
+            video,
+            audio: selection.audio,
         };
         self.download_and_merge(&source).await?;
+        // with `no_merge`, the video stream is the output
+        let extra_paths: Vec<PathBuf> = [&source.video_stream_path, &source.audio_stream_path]
+            .into_iter()
+            .flatten()
+            .filter(|path| **path != source.output_path)
+            .cloned()
+            .collect();
+        if let Some(entry) = &archived {
+            let stale =
+                |path: &&PathBuf| **path != source.output_path && !extra_paths.contains(path);
+            for old_path in entry.paths().filter(stale) {
+                if let Err(e) = fs::remove_file(old_path) {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        self.logger
+                            .warn(&format!("无法删除升级前的文件 {}: {e}", old_path.display()));
+                    }
+                } else {
+                    self.logger
+                        .info(&format!("已删除升级前的文件 {}", old_path.display()));
+                }
+            }
+        }
+        // previews are not recorded, so that the full video is downloaded once available
+        if let (Some(archive), false) = (&self.options.archive, is_preview) {
+            archive.borrow_mut().record(ArchiveEntry {
+                bvid: metadata.bvid,
+                cid: metadata.cid,
+                quality: source.video.id,
+                path: source.output_path,
+                extra_paths,
+            })?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs};
+
+    use tempdir::TempDir;
+
+    use super::{DownloadOptions, Downloader, OnExists, PreviewAction};
+    use crate::{
+        archive::Archive,
+        bilibili::{Account, Resource, VideoPreferences},
+        crawler::MockFetching,
+        fixtures,
+        logger::Logger,
+        muxer::{Capabilities, Container, MockMuxer},
+        sanitize::{FilenameProfile, Sanitizer},
+    };
+
+    fn mp4_muxer() -> MockMuxer {
+        let mut muxer = MockMuxer::new();
+        muxer.expect_name().return_const("mock");
+        muxer.expect_capabilities().return_const(Capabilities {
+            containers: &[Container::Mp4, Container::Mkv],
+            ..Default::default()
+        });
+        muxer
+    }
+
+    fn options(output_dir: &std::path::Path, output: &str) -> DownloadOptions {
+        DownloadOptions {
+            quality: None,
+            format: None,
+            preferences: VideoPreferences::default(),
+            on_preview: PreviewAction::Fail,
+            output_dir: output_dir.to_owned(),
+            output: output.parse().unwrap(),
+            sanitizer: Sanitizer {
+                profile: FilenameProfile::Posix,
+                max_bytes: 255,
+            },
+            work_dir: output_dir.join(".work"),
+            on_exists: OnExists::Skip,
+            archive: None,
+            upgrade: false,
+            container: None,
+            no_merge: false,
+            keep_streams: false,
+            account: Account::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn skip_existing_without_requests() {
+        let dir = TempDir::new("download").unwrap();
+        fs::write(dir.path().join("BV17x411w7KC_p2.mp4"), "").unwrap();
+        let logger = Logger::new(0);
+        // any request panics as no expectation is set
+        let crawler = MockFetching::new();
+        let muxer = mp4_muxer();
+        let downloader = Downloader::new(
+            &logger,
+            &crawler,
+            &muxer,
+            options(dir.path(), "{bvid}_p{page}"),
+        );
+        downloader.download("BV17x411w7KC?p=2").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rerun_leaves_existing_file_alone() {
+        let dir = TempDir::new("download").unwrap();
+        let path = dir.path().join("【MV】保加利亚妖王AZIS视频合辑.mp4");
+        fs::write(&path, "old").unwrap();
+        let logger = Logger::new(0);
+        let mut crawler = MockFetching::new();
+        // only the video page, the streams are not downloaded
+        crawler
+            .expect_fetch_body()
+            .times(1)
+            .returning(fixtures::body("video.html"));
+        let muxer = mp4_muxer();
+        let downloader = Downloader::new(&logger, &crawler, &muxer, options(dir.path(), "{title}"));
+        downloader.download("BV17x411w7KC").await.unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn upgrade_skips_without_higher_quality() {
+        let dir = TempDir::new("download").unwrap();
+        let archive_path = dir.path().join("archive.txt");
+        fs::write(&archive_path, "BV17x411w7KC 279786 80 a.mp4\n").unwrap();
+        let logger = Logger::new(0);
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .times(1)
+            .returning(fixtures::body("video.html"));
+        // 80 is the best quality in the fixture, so nothing should be downloaded
+        crawler.expect_download_to().never();
+        let options = DownloadOptions {
+            archive: Some(RefCell::new(Archive::open(&archive_path, &logger).unwrap())),
+            upgrade: true,
+            ..options(dir.path(), "{title}")
+        };
+        let muxer = mp4_muxer();
+        let downloader = Downloader::new(&logger, &crawler, &muxer, options);
+        downloader.download("BV17x411w7KC").await.unwrap();
+    }
+
+    #[test]
+    fn choose_container() {
+        let dir = TempDir::new("download").unwrap();
+        let logger = Logger::new(0);
+        let crawler = MockFetching::new();
+        let audio = |codecs: &str| Resource {
+            id: 0,
+            base_url: String::new(),
+            bandwidth: 0,
+            codecs: codecs.to_owned(),
+            width: 0,
+            height: 0,
+            frame_rate: 0.0,
+        };
+        let container = |muxer: &MockMuxer, requested: Option<Container>, codecs: &str| {
+            let options = DownloadOptions {
+                container: requested,
+                ..options(dir.path(), "{title}")
+            };
+            Downloader::new(&logger, &crawler, muxer, options)
+                .container(Some(&audio(codecs)))
+                .ok()
+        };
+
+        let muxer = mp4_muxer();
+        assert_eq!(container(&muxer, None, "mp4a.40.2"), Some(Container::Mp4));
+        assert_eq!(container(&muxer, None, "fLaC"), Some(Container::Mkv));
+        assert_eq!(
+            container(&muxer, Some(Container::Mp4), "fLaC"),
+            Some(Container::Mkv)
+        );
+        assert_eq!(
+            container(&muxer, Some(Container::Mkv), "ec-3"),
+            Some(Container::Mkv)
+        );
+
+        let mut mkv_only = MockMuxer::new();
+        mkv_only.expect_name().return_const("mkvmerge");
+        mkv_only.expect_capabilities().return_const(Capabilities {
+            containers: &[Container::Mkv],
+            ..Default::default()
+        });
+        assert_eq!(
+            container(&mkv_only, None, "mp4a.40.2"),
+            Some(Container::Mkv)
+        );
+        assert_eq!(
+            container(&mkv_only, Some(Container::Mov), "mp4a.40.2"),
+            Some(Container::Mkv)
+        );
+
+        let mut mp4_only = MockMuxer::new();
+        mp4_only.expect_name().return_const("native");
+        mp4_only.expect_capabilities().return_const(Capabilities {
+            containers: &[Container::Mp4],
+            ..Default::default()
+        });
+        assert_eq!(container(&mp4_only, None, "ec-3"), Some(Container::Mp4));
+        assert_eq!(
+            container(&mp4_only, None, "fLaC"),
+            None,
+            "FLAC should not be written into MP4"
+        );
+        assert_eq!(container(&mp4_only, Some(Container::Mkv), "fLaC"), None);
+    }
+
+    #[tokio::test]
+    async fn skip_offline_only_if_container_is_known() {
+        let dir = TempDir::new("download").unwrap();
+        fs::write(dir.path().join("BV17x411w7KC.mp4"), "").unwrap();
+        let logger = Logger::new(0);
+        let mut crawler = MockFetching::new();
+        // the selector may pick FLAC, which is saved as .mkv, so the page has to be fetched
+        crawler
+            .expect_fetch_body()
+            .times(1)
+            .returning(|_| Err(anyhow::anyhow!("offline")));
+        let muxer = mp4_muxer();
+        let options = DownloadOptions {
+            format: Some("video+audio[codec=flac]/best".parse().unwrap()),
+            ..options(dir.path(), "{bvid}")
+        };
+        let downloader = Downloader::new(&logger, &crawler, &muxer, options);
+        assert!(downloader.download("BV17x411w7KC").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn upgrade_unwanted_dolby_vision() {
+        let dir = TempDir::new("download").unwrap();
+        let archive_path = dir.path().join("archive.txt");
+        // 126 is a higher id than 80, but Dolby Vision is not allowed by default
+        fs::write(&archive_path, "BV17x411w7KC 279786 126 a.mp4\n").unwrap();
+        let logger = Logger::new(0);
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .times(1)
+            .returning(fixtures::body("video.html"));
+        crawler
+            .expect_download_to()
+            .times(2)
+            .returning(|url, output| Ok(fs::write(output, url)?));
+        let mut muxer = mp4_muxer();
+        muxer
+            .expect_mux()
+            .times(1)
+            .returning(|_, _, output, _| Ok(fs::write(output, "1080p")?));
+        let options = DownloadOptions {
+            archive: Some(RefCell::new(Archive::open(&archive_path, &logger).unwrap())),
+            upgrade: true,
+            ..options(dir.path(), "{bvid}")
+        };
+        let downloader = Downloader::new(&logger, &crawler, &muxer, options);
+        downloader.download("BV17x411w7KC").await.unwrap();
+
+        let archive = Archive::open(&archive_path, &logger).unwrap();
+        assert_eq!(archive.find("BV17x411w7KC", 279786).unwrap().quality, 80);
+    }
+
+    #[tokio::test]
+    async fn upgrade_renames_by_quality() {
+        let dir = TempDir::new("download").unwrap();
+        let old_path = dir.path().join("BV17x411w7KC.高清 720P.mp4");
+        fs::write(&old_path, "720p").unwrap();
+        let archive_path = dir.path().join("archive.txt");
+        fs::write(
+            &archive_path,
+            format!("BV17x411w7KC 279786 64 {}\n", old_path.display()),
+        )
+        .unwrap();
+        let logger = Logger::new(0);
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .returning(fixtures::body("video.html"));
+        crawler
+            .expect_download_to()
+            .returning(|url, output| Ok(fs::write(output, url)?));
+        let mut muxer = mp4_muxer();
+        muxer
+            .expect_mux()
+            .returning(|_, _, output, _| Ok(fs::write(output, "1080p")?));
+        let options = DownloadOptions {
+            archive: Some(RefCell::new(Archive::open(&archive_path, &logger).unwrap())),
+            upgrade: true,
+            ..options(dir.path(), "{bvid}.{quality}")
+        };
+        let downloader = Downloader::new(&logger, &crawler, &muxer, options);
+        downloader.download("BV17x411w7KC").await.unwrap();
+
+        let new_path = dir.path().join("BV17x411w7KC.高清 1080P.mp4");
+        assert_eq!(fs::read_to_string(&new_path).unwrap(), "1080p");
+        assert!(!old_path.exists(), "the old file should be removed");
+        let archive = Archive::open(&archive_path, &logger).unwrap();
+        assert_eq!(archive.find("BV17x411w7KC", 279786).unwrap().path, new_path);
+    }
+
+    #[tokio::test]
+    async fn skip_archived_before_play_info() {
+        let dir = TempDir::new("download").unwrap();
+        let archive_path = dir.path().join("archive.txt");
+        fs::write(&archive_path, "BV1mP4y1x7Ab 1002 80 a.mp4\n").unwrap();
+        let logger = Logger::new(0);
+        let mut crawler = MockFetching::new();
+        // the page has no __playinfo__, so a second request would be the playurl API
+        crawler
+            .expect_fetch_body()
+            .times(1)
+            .returning(fixtures::body("video_multi_part.html"));
+        let options = DownloadOptions {
+            archive: Some(RefCell::new(Archive::open(&archive_path, &logger).unwrap())),
+            ..options(dir.path(), "{title}")
+        };
+        let muxer = mp4_muxer();
+        let downloader = Downloader::new(&logger, &crawler, &muxer, options);
+        downloader.download("BV1mP4y1x7Ab?p=2").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn list_formats_writes_only_json() {
+        let dir = TempDir::new("download").unwrap();
+        // logs at the highest level should not end up in the output
+        let logger = Logger::new(7);
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .returning(fixtures::body("video.html"));
+        let muxer = mp4_muxer();
+        let downloader = Downloader::new(&logger, &crawler, &muxer, options(dir.path(), "{bvid}"));
+        let mut out = Vec::new();
+        downloader
+            .list_formats("BV17x411w7KC", true, &mut out)
+            .await
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(json["bvid"], "BV17x411w7KC");
+        assert!(out.ends_with("}\n"));
+    }
+
+    #[tokio::test]
+    async fn download_and_mux() {
+        let dir = TempDir::new("download").unwrap();
+        let logger = Logger::new(0);
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .returning(fixtures::body("video.html"));
+        crawler
+            .expect_download_to()
+            .times(2)
+            .returning(|url, output| Ok(fs::write(output, url)?));
+        let mut muxer = mp4_muxer();
+        muxer
+            .expect_mux()
+            .times(1)
+            .returning(|video, audio, output, _| {
+                let merged = [fs::read(video)?, fs::read(audio)?].concat();
+                Ok(fs::write(output, merged)?)
+            });
+        let downloader = Downloader::new(&logger, &crawler, &muxer, options(dir.path(), "{bvid}"));
+        downloader.download("BV17x411w7KC").await.unwrap();
+
+        let merged = fs::read_to_string(dir.path().join("BV17x411w7KC.mp4")).unwrap();
+        assert!(merged.ends_with(
+            "279786-1-100050.m4s\
+            https://upos-sz-mirrorcos.bilivideo.com/upgcxcode/86/97/279786/279786-1-30280.m4s"
+        ));
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(
+            files.len(),
+            2,
+            "only the output and the work directory should be left"
+        );
+        assert_eq!(
+            fs::read_dir(dir.path().join(".work")).unwrap().count(),
+            0,
+            "intermediate files should be removed"
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_raw_streams() {
+        let logger = Logger::new(0);
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .returning(fixtures::body("video.html"));
+        crawler
+            .expect_download_to()
+            .returning(|url, output| Ok(fs::write(output, url)?));
+        let list = |dir: &std::path::Path| {
+            let mut names: Vec<_> = fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name != ".work")
+                .collect();
+            names.sort();
+            names
+        };
+
+        let dir = TempDir::new("download").unwrap();
+        let mut muxer = mp4_muxer();
+        muxer.expect_mux().never();
+        let no_merge = DownloadOptions {
+            no_merge: true,
+            ..options(dir.path(), "{bvid}")
+        };
+        let downloader = Downloader::new(&logger, &crawler, &muxer, no_merge);
+        downloader.download("BV17x411w7KC").await.unwrap();
+        assert_eq!(
+            list(dir.path()),
+            ["BV17x411w7KC.1080p.avc.mp4", "BV17x411w7KC.319k.aac.m4a"]
+        );
+
+        let dir = TempDir::new("download").unwrap();
+        let mut muxer = mp4_muxer();
+        muxer
+            .expect_mux()
+            .times(1)
+            .returning(|_, _, output, _| Ok(fs::write(output, "merged")?));
+        let keep_streams = DownloadOptions {
+            keep_streams: true,
+            ..options(dir.path(), "{bvid}")
+        };
+        let downloader = Downloader::new(&logger, &crawler, &muxer, keep_streams);
+        downloader.download("BV17x411w7KC").await.unwrap();
+        assert_eq!(
+            list(dir.path()),
+            [
+                "BV17x411w7KC.1080p.avc.mp4",
+                "BV17x411w7KC.319k.aac.m4a",
+                "BV17x411w7KC.mp4"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_streams_follow_on_exists() {
+        let logger = Logger::new(0);
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .returning(fixtures::body("video.html"));
+        crawler
+            .expect_download_to()
+            .returning(|url, output| Ok(fs::write(output, url)?));
+        let mut muxer = mp4_muxer();
+        muxer
+            .expect_mux()
+            .returning(|_, _, output, _| Ok(fs::write(output, "merged")?));
+
+        for (on_exists, new_audio) in [
+            (OnExists::Skip, None),
+            (OnExists::Rename, Some("BV17x411w7KC (1).319k.aac.m4a")),
+        ] {
+            let dir = TempDir::new("download").unwrap();
+            let old_audio = dir.path().join("BV17x411w7KC.319k.aac.m4a");
+            fs::write(&old_audio, "old").unwrap();
+            let options = DownloadOptions {
+                keep_streams: true,
+                on_exists,
+                ..options(dir.path(), "{bvid}")
+            };
+            let downloader = Downloader::new(&logger, &crawler, &muxer, options);
+            downloader.download("BV17x411w7KC").await.unwrap();
+            assert_eq!(fs::read_to_string(&old_audio).unwrap(), "old");
+            assert!(dir.path().join("BV17x411w7KC.1080p.avc.mp4").exists());
+            if let Some(new_audio) = new_audio {
+                assert!(dir.path().join(new_audio).exists());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrade_replaces_both_streams() {
+        let dir = TempDir::new("download").unwrap();
+        let old_video = dir.path().join("BV17x411w7KC.720p.avc.mp4");
+        let old_audio = dir.path().join("BV17x411w7KC.128k.aac.m4a");
+        fs::write(&old_video, "720p").unwrap();
+        fs::write(&old_audio, "128k").unwrap();
+        let archive_path = dir.path().join("archive.txt");
+        fs::write(
+            &archive_path,
+            format!(
+                "BV17x411w7KC 279786 64 {}\t{}\n",
+                old_video.display(),
+                old_audio.display()
+            ),
+        )
+        .unwrap();
+        let logger = Logger::new(0);
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .returning(fixtures::body("video.html"));
+        crawler
+            .expect_download_to()
+            .returning(|url, output| Ok(fs::write(output, url)?));
+        let mut muxer = mp4_muxer();
+        muxer.expect_mux().never();
+        let options = DownloadOptions {
+            archive: Some(RefCell::new(Archive::open(&archive_path, &logger).unwrap())),
+            upgrade: true,
+            no_merge: true,
+            ..options(dir.path(), "{bvid}")
+        };
+        let downloader = Downloader::new(&logger, &crawler, &muxer, options);
+        downloader.download("BV17x411w7KC").await.unwrap();
+
+        assert!(!old_video.exists(), "the old video should be removed");
+        assert!(!old_audio.exists(), "the old audio should be removed");
+        let archive = Archive::open(&archive_path, &logger).unwrap();
+        let entry = archive.find("BV17x411w7KC", 279786).unwrap();
+        assert_eq!(entry.path, dir.path().join("BV17x411w7KC.1080p.avc.mp4"));
+        assert_eq!(
+            entry.extra_paths,
+            [dir.path().join("BV17x411w7KC.319k.aac.m4a")]
+        );
+        assert!(entry.paths().all(|path| path.exists()));
+    }
+
+    #[tokio::test]
+    async fn clean_up_on_mux_failure() {
+        let dir = TempDir::new("download").unwrap();
+        let logger = Logger::new(0);
+        let mut crawler = MockFetching::new();
+        crawler
+            .expect_fetch_body()
+            .returning(fixtures::body("video.html"));
+        crawler
+            .expect_download_to()
+            .times(2)
+            .returning(|url, output| Ok(fs::write(output, url)?));
+        let mut muxer = mp4_muxer();
+        muxer.expect_mux().times(1).returning(|_, _, output, _| {
+            fs::write(output, "partial")?;
+            Err(anyhow::anyhow!("mux failed"))
+        });
+        let downloader = Downloader::new(&logger, &crawler, &muxer, options(dir.path(), "{bvid}"));
+        assert!(downloader.download("BV17x411w7KC").await.is_err());
+
+        assert!(!dir.path().join("BV17x411w7KC.mp4").exists());
+        assert_eq!(
+            fs::read_dir(dir.path().join(".work")).unwrap().count(),
+            0,
+            "intermediate files should be removed on errors"
+        );
+    }
+}
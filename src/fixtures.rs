@@ -0,0 +1,20 @@
+//! Saved pages in `fixtures/` for tests.
+
+use anyhow::Result;
+use scraper::Html;
+
+pub fn read(name: &str) -> String {
+    let path = format!("{}/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+    std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("failed to read {path}: {e}"))
+}
+
+pub fn html(name: &str) -> Html {
+    Html::parse_document(&read(name))
+}
+
+/// Answers any request with the fixture, e.g.
+/// `crawler.expect_fetch_body().returning(fixtures::body("video.html"))`.
+pub fn body(name: &str) -> impl FnMut(&str) -> Result<Vec<u8>> + Send + 'static {
+    let body = read(name).into_bytes();
+    move |_| Ok(body.clone())
+}
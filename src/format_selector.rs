@@ -0,0 +1,475 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use crate::bilibili::{Resource, VideoInfo, VideoPreferences};
+
+/// A format selector like `video[height<=1080][codec=avc]+audio[bandwidth>=128k]/best`.
+///
+/// Alternatives are separated by `/` and the first one that can be satisfied is used.
+/// An alternative is `best`, `worst`, or `video[...]` optionally followed by `+audio[...]`.
+/// The best audio is used if the audio part is omitted. FLAC and Dolby (E-AC-3) audio are only
+/// used if the filters rule out the AAC ones, e.g. `audio[codec=flac]`.
+#[derive(Debug, PartialEq)]
+pub struct FormatSelector {
+    alternatives: Vec<Alternative>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Alternative {
+    Best,
+    Worst,
+    Tracks {
+        video: Vec<Filter>,
+        audio: Vec<Filter>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Field {
+    /// Quality id for videos, audio id for audios.
+    Id,
+    Width,
+    Height,
+    Fps,
+    Codec,
+    Bandwidth,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, PartialEq)]
+struct Filter {
+    field: Field,
+    op: Op,
+    value: Value,
+}
+
+pub struct Selection {
+    pub video: Resource,
+    pub audio: Resource,
+}
+
+/// Maps codecs like `avc1.640032` to their family name, e.g. `avc`.
+pub fn codec_family(codecs: &str) -> &'static str {
+    let codecs = codecs.to_ascii_lowercase();
+    if codecs.starts_with("avc") {
+        "avc"
+    } else if codecs.starts_with("hev") || codecs.starts_with("hvc") {
+        "hevc"
+    } else if codecs.starts_with("av01") {
+        "av1"
+    } else if codecs.starts_with("mp4a") {
+        "aac"
+    } else if codecs.starts_with("ec-3") {
+        "eac3"
+    } else if codecs.starts_with("flac") {
+        "flac"
+    } else {
+        "unknown"
+    }
+}
+
+fn parse_number(value: &str) -> Option<f64> {
+    let (number, multiplier) = match value.chars().last()? {
+        'k' | 'K' => (&value[..value.len() - 1], 1_000.0),
+        'm' | 'M' => (&value[..value.len() - 1], 1_000_000.0),
+        _ => (value, 1.0),
+    };
+    number.parse::<f64>().ok().map(|n| n * multiplier)
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(filter: &str) -> Result<Self> {
+        // longer operators must be tried first
+        let ops = [
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("!=", Op::Ne),
+            ("=", Op::Eq),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ];
+        let Some((idx, op_str, op)) = ops
+            .iter()
+            .filter_map(|(op_str, op)| filter.find(op_str).map(|idx| (idx, *op_str, *op)))
+            .min_by_key(|(idx, op_str, _)| (*idx, usize::MAX - op_str.len()))
+        else {
+            return Err(anyhow!("missing operator in filter '[{filter}]'"));
+        };
+        let field = match filter[..idx].trim() {
+            "id" | "quality" => Field::Id,
+            "width" => Field::Width,
+            "height" => Field::Height,
+            "fps" => Field::Fps,
+            "codec" | "vcodec" | "acodec" => Field::Codec,
+            "bandwidth" | "bitrate" => Field::Bandwidth,
+            field => return Err(anyhow!("unknown field '{field}' in filter '[{filter}]'")),
+        };
+        let value = filter[idx + op_str.len()..].trim();
+        let value = if field == Field::Codec {
+            if !matches!(op, Op::Eq | Op::Ne) {
+                return Err(anyhow!("codec only supports = and != in '[{filter}]'"));
+            }
+            Value::Text(value.to_ascii_lowercase())
+        } else {
+            Value::Number(
+                parse_number(value)
+                    .ok_or_else(|| anyhow!("invalid number '{value}' in filter '[{filter}]'"))?,
+            )
+        };
+        Ok(Filter { field, op, value })
+    }
+}
+
+impl Filter {
+    fn matches(&self, resource: &Resource) -> bool {
+        match &self.value {
+            Value::Text(codec) => {
+                let matched = codec_family(&resource.codecs) == codec
+                    || resource
+                        .codecs
+                        .to_ascii_lowercase()
+                        .starts_with(codec.as_str());
+                (self.op == Op::Eq) == matched
+            }
+            Value::Number(expected) => {
+                let actual = match self.field {
+                    Field::Id => resource.id as f64,
+                    Field::Width => resource.width as f64,
+                    Field::Height => resource.height as f64,
+                    Field::Fps => resource.frame_rate,
+                    Field::Bandwidth => resource.bandwidth as f64,
+                    Field::Codec => unreachable!("codec is always compared as text"),
+                };
+                match self.op {
+                    Op::Eq => actual == *expected,
+                    Op::Ne => actual != *expected,
+                    Op::Lt => actual < *expected,
+                    Op::Le => actual <= *expected,
+                    Op::Gt => actual > *expected,
+                    Op::Ge => actual >= *expected,
+                }
+            }
+        }
+    }
+}
+
+/// Parses `name[filter][filter]`, returns the name and the filters.
+fn parse_track(track: &str) -> Result<(&str, Vec<Filter>)> {
+    let (name, mut rest) = match track.find('[') {
+        Some(idx) => (&track[..idx], &track[idx..]),
+        None => (track, ""),
+    };
+    let mut filters = Vec::new();
+    while !rest.is_empty() {
+        let Some(inner) = rest.strip_prefix('[') else {
+            return Err(anyhow!("unexpected '{rest}' in '{track}'"));
+        };
+        let Some(end) = inner.find(']') else {
+            return Err(anyhow!("missing ']' in '{track}'"));
+        };
+        filters.push(inner[..end].parse()?);
+        rest = &inner[end + 1..];
+    }
+    Ok((name.trim(), filters))
+}
+
+impl FromStr for Alternative {
+    type Err = anyhow::Error;
+
+    fn from_str(alternative: &str) -> Result<Self> {
+        match alternative.trim() {
+            "best" => return Ok(Alternative::Best),
+            "worst" => return Ok(Alternative::Worst),
+            _ => {}
+        }
+        let mut video = None;
+        let mut audio = None;
+        for track in alternative.split('+') {
+            match parse_track(track.trim())? {
+                ("video" | "bv", filters) if video.is_none() => video = Some(filters),
+                ("audio" | "ba", filters) if audio.is_none() => audio = Some(filters),
+                (name, _) => return Err(anyhow!("unexpected '{name}' in '{alternative}'")),
+            }
+        }
+        let Some(video) = video else {
+            return Err(anyhow!("video is required in '{alternative}'"));
+        };
+        Ok(Alternative::Tracks {
+            video,
+            audio: audio.unwrap_or_default(),
+        })
+    }
+}
+
+impl FromStr for FormatSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(selector: &str) -> Result<Self> {
+        let alternatives = selector
+            .split('/')
+            .map(|alternative| alternative.parse())
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| anyhow!("invalid format selector '{selector}': {e}"))?;
+        Ok(FormatSelector { alternatives })
+    }
+}
+
+fn best_match<'a, K: Ord>(
+    resources: impl Iterator<Item = &'a Resource>,
+    filters: &[Filter],
+    rank: impl Fn(&Resource) -> K,
+) -> Option<&'a Resource> {
+    resources
+        .filter(|r| filters.iter().all(|f| f.matches(r)))
+        .max_by_key(|r| rank(r))
+}
+
+/// Sort key of audios, the larger the better. AAC is preferred over FLAC and E-AC-3, which are
+/// larger and do not fit in every container.
+fn audio_rank(audio: &Resource) -> (bool, u32) {
+    let lossless_or_dolby = matches!(codec_family(&audio.codecs), "flac" | "eac3");
+    (!lossless_or_dolby, audio.bandwidth)
+}
+
+impl FormatSelector {
+    pub fn best() -> Self {
+        FormatSelector {
+            alternatives: vec![Alternative::Best],
+        }
+    }
+
+    /// `video[quality<=<quality>]+audio/worst`, the cap is never exceeded unless even the lowest
+    /// quality is above it.
+    pub fn with_max_quality(quality: u8) -> Self {
+        FormatSelector {
+            alternatives: vec![
+                Alternative::Tracks {
+                    video: vec![Filter {
+                        field: Field::Id,
+                        op: Op::Le,
+                        value: Value::Number(quality.into()),
+                    }],
+                    audio: vec![],
+                },
+                Alternative::Worst,
+            ],
+        }
+    }
+
+    /// Selects the first alternative that can be satisfied by the videos allowed by `preferences`.
+    pub fn select(
+        &self,
+        video_info: &VideoInfo,
+        preferences: &VideoPreferences,
+    ) -> Option<Selection> {
+        let videos = || video_info.allowed_videos(preferences);
+        let rank = |v: &Resource| preferences.rank(v);
+        self.alternatives.iter().find_map(|alternative| {
+            let (video, audio) = match alternative {
+                Alternative::Best => (
+                    best_match(videos(), &[], rank)?,
+                    best_match(video_info.audio.iter(), &[], audio_rank)?,
+                ),
+                Alternative::Worst => (
+                    best_match(videos(), &[], |v| std::cmp::Reverse(rank(v)))?,
+                    best_match(video_info.audio.iter(), &[], |a| {
+                        let (preferred, bandwidth) = audio_rank(a);
+                        (preferred, std::cmp::Reverse(bandwidth))
+                    })?,
+                ),
+                Alternative::Tracks { video, audio } => (
+                    best_match(videos(), video, rank)?,
+                    best_match(video_info.audio.iter(), audio, audio_rank)?,
+                ),
+            };
+            Some(Selection {
+                video: video.clone(),
+                audio: audio.clone(),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FormatSelector;
+    use crate::bilibili::{Resource, VideoInfo, VideoPreferences};
+
+    fn resource(id: u32, codecs: &str, height: u32, frame_rate: f64, bandwidth: u32) -> Resource {
+        Resource {
+            id,
+            base_url: format!("{id}-{codecs}"),
+            bandwidth,
+            codecs: codecs.to_owned(),
+            width: height * 16 / 9,
+            height,
+            frame_rate,
+        }
+    }
+
+    fn video_info() -> VideoInfo {
+        VideoInfo {
+            accept_description: vec![],
+            accept_quality: vec![126, 125, 120, 116, 80, 64],
+            duration: 60,
+            is_preview: false,
+            video: vec![
+                resource(126, "hev1.2.4.L153.90", 2160, 30.0, 12_000_000),
+                resource(125, "hev1.2.4.L153.90", 2160, 30.0, 10_000_000),
+                resource(120, "hev1.1.6.L153.90", 2160, 30.0, 8_000_000),
+                resource(116, "avc1.640032", 1080, 60.0, 4_000_000),
+                resource(80, "avc1.640032", 1080, 30.0, 2_000_000),
+                resource(80, "hev1.1.6.L150.90", 1080, 30.0, 1_000_000),
+                resource(64, "avc1.640028", 720, 30.0, 1_000_000),
+            ],
+            audio: vec![
+                resource(30216, "mp4a.40.2", 0, 0.0, 64_000),
+                resource(30280, "mp4a.40.2", 0, 0.0, 320_000),
+                resource(30250, "ec-3", 0, 0.0, 768_000),
+                resource(30251, "fLaC", 0, 0.0, 2_000_000),
+            ],
+        }
+    }
+
+    fn select(selector: &str) -> Option<(String, u32)> {
+        let selector: FormatSelector = selector.parse().unwrap();
+        selector
+            .select(&video_info(), &VideoPreferences::default())
+            .map(|s| (s.video.base_url, s.audio.id))
+    }
+
+    #[test]
+    fn select_best_and_worst() {
+        assert_eq!(
+            select("best"),
+            Some(("120-hev1.1.6.L153.90".to_owned(), 30280))
+        );
+        assert_eq!(select("worst"), Some(("64-avc1.640028".to_owned(), 30216)));
+    }
+
+    #[test]
+    fn select_with_filters() {
+        assert_eq!(
+            select("video[height<=1080][codec=avc]+audio[bandwidth<128k]"),
+            Some(("116-avc1.640032".to_owned(), 30216))
+        );
+        assert_eq!(
+            select("video[fps<50][codec!=avc]"),
+            Some(("120-hev1.1.6.L153.90".to_owned(), 30280))
+        );
+        assert_eq!(
+            select("video[quality=80][codec=hev1]"),
+            Some(("80-hev1.1.6.L150.90".to_owned(), 30280))
+        );
+    }
+
+    #[test]
+    fn select_lossless_and_dolby_audio_only_if_asked() {
+        assert_eq!(
+            select("video[height=720]+audio[codec=flac]"),
+            Some(("64-avc1.640028".to_owned(), 30251))
+        );
+        assert_eq!(
+            select("video[height=720]+audio[codec=eac3]"),
+            Some(("64-avc1.640028".to_owned(), 30250))
+        );
+        assert_eq!(
+            select("video[height=720]+audio[bandwidth>=128k]"),
+            Some(("64-avc1.640028".to_owned(), 30280))
+        );
+    }
+
+    #[test]
+    fn select_falls_back() {
+        assert_eq!(
+            select("video[height>2160]/video[codec=av1]/video[height=720]"),
+            Some(("64-avc1.640028".to_owned(), 30280))
+        );
+        assert_eq!(select("video[height>2160]+audio"), None);
+        assert_eq!(
+            FormatSelector::with_max_quality(100)
+                .select(&video_info(), &VideoPreferences::default())
+                .map(|s| s.video.base_url),
+            Some("80-avc1.640032".to_owned())
+        );
+        assert_eq!(
+            FormatSelector::with_max_quality(32)
+                .select(&video_info(), &VideoPreferences::default())
+                .map(|s| s.video.base_url),
+            Some("64-avc1.640028".to_owned()),
+            "the lowest quality should be used if all are above the cap"
+        );
+    }
+
+    #[test]
+    fn select_with_preferences() {
+        let select = |preferences: VideoPreferences| {
+            FormatSelector::best()
+                .select(&video_info(), &preferences)
+                .map(|s| s.video.base_url)
+        };
+        let hdr = VideoPreferences {
+            allow_hdr: true,
+            ..Default::default()
+        };
+        assert_eq!(select(hdr), Some("125-hev1.2.4.L153.90".to_owned()));
+        let dolby_vision = VideoPreferences {
+            allow_dolby_vision: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            select(dolby_vision),
+            Some("126-hev1.2.4.L153.90".to_owned())
+        );
+
+        let select_1080p = |preferences: VideoPreferences| {
+            "video[height=1080]"
+                .parse::<FormatSelector>()
+                .unwrap()
+                .select(&video_info(), &preferences)
+                .map(|s| s.video.id)
+        };
+        assert_eq!(select_1080p(VideoPreferences::default()), Some(116));
+        let low_fps = VideoPreferences {
+            prefer_high_fps: false,
+            ..Default::default()
+        };
+        assert_eq!(select_1080p(low_fps), Some(80));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for selector in [
+            "",
+            "audio",
+            "video[size>1]",
+            "video[height>abc]",
+            "video[codec>avc]",
+            "video[height>1",
+            "video+video",
+        ] {
+            assert!(
+                selector.parse::<FormatSelector>().is_err(),
+                "'{selector}' should be rejected"
+            );
+        }
+    }
+}
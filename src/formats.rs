@@ -0,0 +1,150 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::bilibili::{VideoInfo, VideoMetadata};
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct VideoFormat {
+    pub id: u32,
+    pub description: String,
+    pub width: u32,
+    pub height: u32,
+    pub codecs: String,
+    pub frame_rate: f64,
+    pub bandwidth: u32,
+    pub estimated_size: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AudioFormat {
+    pub id: u32,
+    pub codecs: String,
+    pub bandwidth: u32,
+    pub estimated_size: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FormatList {
+    pub bvid: String,
+    pub cid: i64,
+    pub title: String,
+    pub duration: u64,
+    pub video: Vec<VideoFormat>,
+    pub audio: Vec<AudioFormat>,
+}
+
+impl FormatList {
+    pub fn new(metadata: &VideoMetadata, title: &str, video_info: &VideoInfo) -> Self {
+        FormatList {
+            bvid: metadata.bvid.clone(),
+            cid: metadata.cid,
+            title: title.to_owned(),
+            duration: video_info.duration,
+            video: video_info
+                .video
+                .iter()
+                .map(|v| VideoFormat {
+                    id: v.id,
+                    description: video_info.get_quality_name(v.id as u8),
+                    width: v.width,
+                    height: v.height,
+                    codecs: v.codecs.clone(),
+                    frame_rate: v.frame_rate,
+                    bandwidth: v.bandwidth,
+                    estimated_size: video_info.estimate_size(v),
+                })
+                .collect(),
+            audio: video_info
+                .audio
+                .iter()
+                .map(|a| AudioFormat {
+                    id: a.id,
+                    codecs: a.codecs.clone(),
+                    bandwidth: a.bandwidth,
+                    estimated_size: video_info.estimate_size(a),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn to_table(&self) -> String {
+        let mut lines = vec![format!("{} ({})", self.title, self.bvid)];
+        lines.push(format!(
+            "{:<6} {:<16} {:<10} {:<20} {:>7} {:>10} {:>10}",
+            "ID", "QUALITY", "RESOLUTION", "CODECS", "FPS", "BITRATE", "SIZE"
+        ));
+        for v in &self.video {
+            lines.push(format!(
+                "{:<6} {:<16} {:<10} {:<20} {:>7.3} {:>10} {:>10}",
+                v.id,
+                v.description,
+                format!("{}x{}", v.width, v.height),
+                v.codecs,
+                v.frame_rate,
+                format_bitrate(v.bandwidth),
+                format_size(v.estimated_size),
+            ));
+        }
+        for a in &self.audio {
+            lines.push(format!(
+                "{:<6} {:<16} {:<10} {:<20} {:>7} {:>10} {:>10}",
+                a.id,
+                "audio",
+                "",
+                a.codecs,
+                "",
+                format_bitrate(a.bandwidth),
+                format_size(a.estimated_size),
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+fn format_bitrate(bandwidth: u32) -> String {
+    format!("{}k", bandwidth / 1000)
+}
+
+fn format_size(bytes: u64) -> String {
+    let mib = bytes as f64 / 1024.0 / 1024.0;
+    if mib >= 1024.0 {
+        format!("{:.2}GiB", mib / 1024.0)
+    } else {
+        format!("{mib:.2}MiB")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FormatList;
+    use crate::{
+        bilibili::{extract_play_info, extract_video_metadata},
+        fixtures,
+    };
+
+    #[test]
+    fn format_list_from_fixture() {
+        let html = fixtures::html("video.html");
+        let metadata = extract_video_metadata(&html).unwrap();
+        let video_info = extract_play_info(&html).unwrap().unwrap();
+        let formats = FormatList::new(&metadata, "title", &video_info);
+
+        assert_eq!(formats.video.len(), 4);
+        let best = &formats.video[0];
+        assert_eq!(best.description, "高清 1080P");
+        assert_eq!((best.width, best.height), (1920, 1080));
+        assert_eq!(best.codecs, "avc1.640032");
+        assert_eq!(best.estimated_size, 1331268 * 195 / 8);
+        assert_eq!(formats.audio[0].id, 30280);
+
+        let table = formats.to_table();
+        assert!(table.contains("1920x1080"));
+        assert!(table.contains("hev1.1.6.L150.90"));
+        let json: serde_json::Value = serde_json::from_str(&formats.to_json().unwrap()).unwrap();
+        assert_eq!(json["video"][2]["id"], 64);
+    }
+}
@@ -2,14 +2,15 @@ use colored::{self, Colorize};
 
 #[repr(u8)]
 #[derive(Clone, Copy)]
-enum SEVERITY {
-    FATAL = 2,
-    WARN = 4,
-    INFO = 5,
-    VERBOSE = 6,
-    DEBUG = 7,
+enum Severity {
+    Fatal = 2,
+    Warn = 4,
+    Info = 5,
+    Verbose = 6,
+    Debug = 7,
 }
 
+/// Writes to stderr, so that stdout only has the output of the command, e.g. `--list-formats --json`.
 pub struct Logger {
     log_level: u8,
 }
@@ -22,37 +23,37 @@ impl Logger {
 
 impl Logger {
     pub fn verbose(&self, message: &str) {
-        if self.log_level >= SEVERITY::VERBOSE as u8 {
+        if self.log_level >= Severity::Verbose as u8 {
             let log_message = format!("[verbose] {message}");
-            println!("{}", log_message.truecolor(128, 128, 128))
+            eprintln!("{}", log_message.truecolor(128, 128, 128))
         }
     }
 
     pub fn fatal(&self, message: &str) {
-        if self.log_level >= SEVERITY::FATAL as u8 {
+        if self.log_level >= Severity::Fatal as u8 {
             let log_message = format!("[fatal] {message}");
-            println!("{}", log_message.red())
+            eprintln!("{}", log_message.red())
         }
     }
 
     pub fn debug(&self, message: &str) {
-        if self.log_level >= SEVERITY::DEBUG as u8 {
+        if self.log_level >= Severity::Debug as u8 {
             let log_message = format!("[debug] {message}");
-            println!("{}", log_message.truecolor(128, 128, 128))
+            eprintln!("{}", log_message.truecolor(128, 128, 128))
         }
     }
 
     pub fn warn(&self, message: &str) {
-        if self.log_level >= SEVERITY::WARN as u8 {
+        if self.log_level >= Severity::Warn as u8 {
             let log_message = format!("[warn] {message}");
-            println!("{}", log_message.yellow())
+            eprintln!("{}", log_message.yellow())
         }
     }
 
     pub fn info(&self, message: &str) {
-        if self.log_level >= SEVERITY::INFO as u8 {
+        if self.log_level >= Severity::Info as u8 {
             let log_message = format!("[info] {message}");
-            println!("{}", log_message.green())
+            eprintln!("{}", log_message.green())
         }
     }
 }
@@ -0,0 +1,267 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use qrcode::{render::unicode::Dense1x2, QrCode};
+
+use crate::{
+    bilibili::{
+        check_cookie_refresh, confirm_cookie_refresh, poll_qrcode, refresh_cookie, request_qrcode,
+        LoginCredentials, QrCodeStatus,
+    },
+    config::{write_config, Config},
+    cookies::CookieJar,
+    crawler::{Crawler, Fetching},
+    logger::Logger,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn render_qrcode(content: &str) -> Result<String> {
+    let code = QrCode::new(content.as_bytes())?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+pub async fn login<F: Fetching>(
+    crawler: &F,
+    mut config: Config,
+    config_path: &str,
+    logger: &Logger,
+) -> Result<()> {
+    let qrcode = request_qrcode(crawler).await?;
+    logger.debug(&format!("qrcode url: {}", qrcode.url));
+    println!("{}", render_qrcode(&qrcode.url)?);
+    logger.info("请使用哔哩哔哩客户端扫描二维码登录");
+
+    let mut scanned = false;
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        match poll_qrcode(crawler, &qrcode.qrcode_key).await? {
+            QrCodeStatus::NotScanned => {}
+            QrCodeStatus::Scanned => {
+                if !scanned {
+                    logger.info("已扫码，请在手机上确认登录");
+                    scanned = true;
+                }
+            }
+            QrCodeStatus::Expired => return Err(anyhow!("二维码已失效，请重新登录")),
+            QrCodeStatus::Confirmed(credentials) => {
+                config.sess_data = credentials.sess_data;
+                config.bili_jct = credentials.bili_jct;
+                config.refresh_token = credentials.refresh_token;
+                write_config(config_path, &config)?;
+                logger.info(&format!("登录成功，已保存到 '{config_path}'"));
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Refreshes the cookies in `config` if bilibili asks for it and persists the new ones.
+/// Failing to refresh is not fatal, the old cookies are kept in that case.
+pub async fn refresh_if_needed(config: Config, config_path: &str, logger: &Logger) -> Config {
+    refresh_with(config, config_path, logger, |sess_data| {
+        Crawler::new(CookieJar::from_sess_data(sess_data), logger)
+    })
+    .await
+}
+
+/// `crawler_for` creates a crawler sending the given SESSDATA.
+async fn refresh_with<F: Fetching>(
+    mut config: Config,
+    config_path: &str,
+    logger: &Logger,
+    crawler_for: impl Fn(&str) -> F,
+) -> Config {
+    if config.sess_data.is_empty() || config.refresh_token.is_empty() {
+        return config;
+    }
+    let crawler = crawler_for(&config.sess_data);
+    let timestamp = match check_cookie_refresh(&crawler, &config.bili_jct).await {
+        Ok(Some(timestamp)) => timestamp,
+        Ok(None) => {
+            logger.debug("cookie does not need to be refreshed");
+            return config;
+        }
+        Err(e) => {
+            logger.warn(&format!("刷新登录信息失败: {e}"));
+            return config;
+        }
+    };
+    let credentials = LoginCredentials {
+        sess_data: config.sess_data.clone(),
+        bili_jct: config.bili_jct.clone(),
+        refresh_token: config.refresh_token.clone(),
+    };
+    let refreshed = match refresh_cookie(&crawler, &credentials, timestamp).await {
+        Ok(refreshed) => refreshed,
+        Err(e) => {
+            logger.warn(&format!("刷新登录信息失败: {e}"));
+            return config;
+        }
+    };
+    // the old cookies are invalid once refreshed, so the new ones are saved before confirming
+    config.sess_data = refreshed.sess_data;
+    config.bili_jct = refreshed.bili_jct;
+    config.refresh_token = refreshed.refresh_token;
+    match write_config(config_path, &config) {
+        Ok(()) => logger.info(&format!("登录信息已刷新，已保存到 '{config_path}'")),
+        Err(e) => logger.warn(&format!("登录信息已刷新，但无法写入 '{config_path}': {e}")),
+    }
+    let refreshed_crawler = crawler_for(&config.sess_data);
+    if let Err(e) = confirm_cookie_refresh(
+        &refreshed_crawler,
+        &config.bili_jct,
+        &credentials.refresh_token,
+    )
+    .await
+    {
+        logger.warn(&format!("无法确认刷新，旧的登录信息可能仍然有效: {e}"));
+    }
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use tempdir::TempDir;
+
+    use super::{login, refresh_with};
+    use crate::{
+        config::{read_config, Config},
+        crawler::{FetchResponse, MockFetching},
+        logger::Logger,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn login_saves_credentials() {
+        let dir = TempDir::new("login").unwrap();
+        let config_path = dir.path().join("config.json");
+        let config_path = config_path.to_str().unwrap();
+        let mut crawler = MockFetching::new();
+        crawler.expect_fetch_body().times(1).returning(|_| {
+            Ok(br#"{"code":0,"data":{"url":"https://qr/1","qrcode_key":"key"}}"#.to_vec())
+        });
+        let polls = AtomicUsize::new(0);
+        crawler
+            .expect_fetch_with_cookies()
+            .times(3)
+            .returning(move |_| {
+                let (code, cookies) = match polls.fetch_add(1, Ordering::SeqCst) {
+                    0 => (86101, vec![]),
+                    1 => (86090, vec![]),
+                    _ => (
+                        0,
+                        vec![
+                            ("SESSDATA".to_owned(), "sess".to_owned()),
+                            ("bili_jct".to_owned(), "jct".to_owned()),
+                        ],
+                    ),
+                };
+                Ok(FetchResponse {
+                    body: format!(
+                        r#"{{"code":0,"data":{{"code":{code},"refresh_token":"token"}}}}"#
+                    )
+                    .into_bytes(),
+                    cookies,
+                })
+            });
+        let logger = Logger::new(0);
+        login(&crawler, Config::default(), config_path, &logger)
+            .await
+            .unwrap();
+        assert_eq!(
+            read_config(config_path, &logger),
+            Config {
+                sess_data: "sess".to_owned(),
+                bili_jct: "jct".to_owned(),
+                refresh_token: "token".to_owned(),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn login_fails_on_expired_qrcode() {
+        let mut crawler = MockFetching::new();
+        crawler.expect_fetch_body().returning(|_| {
+            Ok(br#"{"code":0,"data":{"url":"https://qr/1","qrcode_key":"key"}}"#.to_vec())
+        });
+        crawler.expect_fetch_with_cookies().returning(|_| {
+            Ok(FetchResponse {
+                body: br#"{"code":0,"data":{"code":86038}}"#.to_vec(),
+                cookies: vec![],
+            })
+        });
+        let logger = Logger::new(0);
+        let result = login(&crawler, Config::default(), "unused.json", &logger).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn refresh_persists_before_confirm() {
+        let dir = TempDir::new("login").unwrap();
+        let config_path = dir.path().join("config.json");
+        let config_path = config_path.to_str().unwrap().to_owned();
+        let config = Config {
+            sess_data: "old_sess".to_owned(),
+            bili_jct: "old_jct".to_owned(),
+            refresh_token: "old_token".to_owned(),
+            ..Default::default()
+        };
+
+        let mut old = MockFetching::new();
+        old.expect_fetch_body().times(2).returning(|url| {
+            Ok(if url.contains("/cookie/info") {
+                br#"{"code":0,"message":"0","data":{"refresh":true,"timestamp":1684466082768}}"#
+                    .to_vec()
+            } else {
+                br#"<html><body><div id="1-name">csrf</div></body></html>"#.to_vec()
+            })
+        });
+        old.expect_post_form().times(1).returning(|_, form| {
+            assert!(form.contains(&("refresh_token".to_owned(), "old_token".to_owned())));
+            Ok(FetchResponse {
+                body: br#"{"code":0,"message":"0","data":{"refresh_token":"new_token"}}"#.to_vec(),
+                cookies: vec![
+                    ("SESSDATA".to_owned(), "new_sess".to_owned()),
+                    ("bili_jct".to_owned(), "new_jct".to_owned()),
+                ],
+            })
+        });
+        let mut new = MockFetching::new();
+        let confirm_path = config_path.clone();
+        new.expect_post_form().times(1).returning(move |_, form| {
+            let saved = read_config(&confirm_path, &Logger::new(0));
+            assert_eq!(
+                saved.sess_data, "new_sess",
+                "new cookies should be saved before confirming"
+            );
+            assert!(form.contains(&("refresh_token".to_owned(), "old_token".to_owned())));
+            Err(anyhow::anyhow!("network error"))
+        });
+        let crawlers = RefCell::new(vec![new, old]);
+
+        let logger = Logger::new(0);
+        let refreshed = refresh_with(config, &config_path, &logger, |_| {
+            crawlers.borrow_mut().pop().unwrap()
+        })
+        .await;
+
+        let expected = Config {
+            sess_data: "new_sess".to_owned(),
+            bili_jct: "new_jct".to_owned(),
+            refresh_token: "new_token".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(refreshed, expected);
+        assert_eq!(read_config(&config_path, &logger), expected);
+    }
+}
@@ -1,60 +1,199 @@
+mod archive;
 mod bilibili;
+mod config;
+mod cookies;
 mod crawler;
 mod download;
+#[cfg(test)]
+mod fixtures;
+mod format_selector;
+mod formats;
 mod logger;
+mod login;
+mod mp4;
+mod muxer;
+mod output;
+mod sanitize;
+mod workdir;
 
 use anyhow::Result;
-use download::Downloader;
-use serde::{Deserialize, Serialize};
-use std::fs;
+use archive::Archive;
+use bilibili::{fetch_account, fetch_fingerprint, Account, Fingerprint, VideoPreferences};
+use config::{read_config, Config, CONFIG_PATH};
+use cookies::CookieJar;
+use download::{DownloadOptions, Downloader, OnExists, PreviewAction};
+use format_selector::FormatSelector;
+use output::OutputTemplate;
+use sanitize::{FilenameProfile, Sanitizer};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use crawler::Crawler;
 use logger::Logger;
+use muxer::{create_muxer, Container, FfmpegOptions, Muxer, MuxerKind, NativeMuxer};
+use std::{cell::RefCell, path::PathBuf};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long, default_value_t = 5)]
     log_level: u8,
 
     #[arg(short, long, default_value_t = false)]
     select_quality: bool,
 
+    /// 列出可用的视频和音频格式，不下载
+    #[arg(short = 'F', long, default_value_t = false)]
+    list_formats: bool,
+
+    /// 以 JSON 格式输出 --list-formats 的结果
+    #[arg(long, default_value_t = false, requires = "list_formats")]
+    json: bool,
+
+    /// 从 Netscape 格式的 cookies.txt 导入 cookie
+    #[arg(long, conflicts_with = "cookies_from_firefox")]
+    cookies: Option<PathBuf>,
+
+    /// 从 Firefox 的 profile 目录或 cookies.sqlite 导入 cookie
+    #[arg(long)]
+    cookies_from_firefox: Option<PathBuf>,
+
+    /// 最高清晰度，如 80 (1080P)、116 (1080P60)、120 (4K)，默认下载最高可用清晰度
+    #[arg(short, long)]
+    quality: Option<u8>,
+
+    /// 格式选择表达式，如 "video[height<=1080][codec=avc]+audio[bandwidth>=128k]/best"；FLAC 和杜比音频需要用 audio[codec=flac] 或 audio[codec=eac3] 选择
+    #[arg(short, long, conflicts_with = "quality")]
+    format: Option<String>,
+
+    /// 同分辨率下不优先选择 60fps 等高帧率版本
+    #[arg(long, default_value_t = false)]
+    no_high_fps: bool,
+
+    /// 允许选择 HDR (125)，默认不选择
+    #[arg(long, default_value_t = false)]
+    allow_hdr: bool,
+
+    /// 允许选择杜比视界 (126)，默认不选择
+    #[arg(long, default_value_t = false)]
+    allow_dolby_vision: bool,
+
+    /// 只能获取试看片段时的处理方式：fail 报错，save 保存并在文件名中标记 .preview
+    #[arg(long, value_enum, default_value_t = PreviewAction::Fail)]
+    on_preview: PreviewAction,
+
+    /// 下载目录
+    #[arg(long, default_value = "./download")]
+    output_dir: PathBuf,
+
+    /// 文件名模板，可用字段: {title} {bvid} {aid} {cid} {uploader} {pubdate:%Y-%m-%d} {page}
+    /// {part_title} {quality}，可以用 / 创建子目录
+    #[arg(short, long, default_value = "{title}")]
+    output: String,
+
+    /// 文件名允许的字符：posix 只替换 /，windows 替换 Windows 不允许的字符，ascii 只保留 ASCII 字符
+    #[arg(long, value_enum, default_value_t = FilenameProfile::default())]
+    filename_profile: FilenameProfile,
+
+    /// 文件名和目录名的最大字节数，超过时截断
+    #[arg(long, default_value_t = 255)]
+    filename_max_bytes: usize,
+
+    /// 输出文件已存在时的处理方式：skip 跳过，overwrite 覆盖，rename 另存为 "名称 (1).mp4"；skip 需要先获取视频页面来生成文件名，除非文件名模板只使用 {bvid} {page}
+    #[arg(long, value_enum, default_value_t = OnExists::Skip)]
+    on_exists: OnExists,
+
+    /// 下载记录文件，已记录的视频 (bvid + cid) 不会重复下载，即使文件已被移动或重命名
+    #[arg(long)]
+    download_archive: Option<PathBuf>,
+
+    /// 对下载记录中的视频，如果有更高的清晰度则重新下载并替换原文件
+    #[arg(long, default_value_t = false, requires = "download_archive")]
+    upgrade: bool,
+
+    /// 合并视频和音频的工具，默认使用 ffmpeg，没有 ffmpeg 时使用内置的 MP4 合并；指定了 ffmpeg 的路径或参数时，ffmpeg 不可用会报错
+    #[arg(long, value_enum)]
+    muxer: Option<MuxerKind>,
+
+    /// 输出格式，默认为 mp4，音频为 FLAC 时为 mkv
+    #[arg(long, value_enum)]
+    container: Option<Container>,
+
+    /// ffmpeg 的路径，默认为配置文件中的 "ffmpeg" 或 PATH 中的 ffmpeg
+    #[arg(long)]
+    ffmpeg: Option<PathBuf>,
+
+    /// 传给 ffmpeg 的额外参数，加在配置文件中的 "ffmpeg_extra_args" 之后，可重复，如 --ffmpeg-arg=-movflags --ffmpeg-arg=+faststart
+    #[arg(long = "ffmpeg-arg", allow_hyphen_values = true)]
+    ffmpeg_args: Vec<String>,
+
+    /// 临时文件目录，每个视频使用其中一个随机命名的子目录，完成或失败后删除，默认为配置文件中的 "work_dir" 或系统临时目录
+    #[arg(long)]
+    work_dir: Option<PathBuf>,
+
+    /// 不合并视频和音频，保存为 "名称.1080p.avc.mp4" 和 "名称.128k.aac.m4a"，FLAC 和杜比音频同样保存为 .m4a，如 "名称.1411k.flac.m4a"
+    #[arg(long, default_value_t = false, conflicts_with = "keep_streams")]
+    no_merge: bool,
+
+    /// 合并后保留原始的视频和音频文件，文件名同 --no-merge，已存在时按 --on-exists 处理
+    #[arg(long, default_value_t = false)]
+    keep_streams: bool,
+
     #[clap(index = 1)]
     video_ids: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-struct Config {
-    #[serde(alias = "SESSDATA")]
-    sess_data: String,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 扫码登录，并将 SESSDATA 等信息写入配置文件
+    Login,
 }
 
-fn read_config(path: &str, logger: &Logger) -> Config {
-    match fs::read_to_string(path) {
-        Ok(contents) => match serde_json::from_str::<Config>(&contents) {
-            Ok(config) => {
-                logger.debug(&format!(
-                    "sess_data parsed as '{}' from '{path}'",
-                    config.sess_data
-                ));
-                return config;
-            }
-            Err(_) => {
-                logger.warn("配置文件格式不正确，无法下载高清视频");
-                return Config {
-                    sess_data: "".to_owned(),
-                };
+async fn prepare_cookies(args: &Args, config: &Config, logger: &Logger) -> Result<CookieJar> {
+    let mut cookies = if let Some(path) = &args.cookies {
+        CookieJar::read_netscape(path)?
+    } else if let Some(path) = &args.cookies_from_firefox {
+        CookieJar::read_firefox(path)?
+    } else {
+        CookieJar::default()
+    };
+    if cookies.get("SESSDATA").is_none() && !config.sess_data.is_empty() {
+        cookies.set("SESSDATA", &config.sess_data);
+    }
+    if cookies.get("buvid3").is_none() {
+        let fingerprint = match fetch_fingerprint(&Crawler::new(cookies.clone(), logger)).await {
+            Ok(fingerprint) => fingerprint,
+            Err(e) => {
+                logger.debug(&format!("failed to fetch buvid, generate locally: {e}"));
+                Fingerprint::generate()
             }
-        },
-        Err(_) => {
-            logger.warn(&format!("找不到配置文件 '{path}', 无法下载高清视频"));
-            return Config {
-                sess_data: "".to_owned(),
-            };
+        };
+        logger.debug(&format!("use fingerprint: {:?}", fingerprint));
+        cookies.set("buvid3", &fingerprint.buvid3);
+        cookies.set("buvid4", &fingerprint.buvid4);
+        cookies.set("b_nut", &fingerprint.b_nut);
+    }
+    Ok(cookies)
+}
+
+async fn check_account(crawler: &Crawler<'_>, logger: &Logger) -> Account {
+    let account = match fetch_account(crawler).await {
+        Ok(account) => account,
+        Err(e) => {
+            logger.warn(&format!("无法获取登录状态: {e}"));
+            Account::default()
         }
+    };
+    if account.is_vip {
+        logger.info(&format!("已登录: {}，大会员", account.uname));
+    } else if account.is_login {
+        logger.info(&format!("已登录: {}，非大会员", account.uname));
+    } else {
+        logger.warn("未登录，无法下载高清视频");
     }
+    account
 }
 
 async fn main_inner() -> Result<()> {
@@ -62,14 +201,89 @@ async fn main_inner() -> Result<()> {
     let logger = Logger::new(args.log_level);
     logger.debug(&format!("args are: {:#?}", args));
 
-    let config = read_config("./config.json", &logger);
-    let crawler = Crawler::new(&config.sess_data, &logger);
-    let downloader = Downloader::new(&logger, &crawler);
+    let format = args
+        .format
+        .as_deref()
+        .map(str::parse::<FormatSelector>)
+        .transpose()?;
+    let output = args.output.parse::<OutputTemplate>()?;
+    let archive = args
+        .download_archive
+        .as_deref()
+        .map(|path| Archive::open(path, &logger))
+        .transpose()?
+        .map(RefCell::new);
+    let config = read_config(CONFIG_PATH, &logger);
+
+    if let Some(Command::Login) = args.command {
+        let crawler = Crawler::new(CookieJar::default(), &logger);
+        return login::login(&crawler, config, CONFIG_PATH, &logger).await;
+    }
+
+    // listing formats is read-only, so the config is not refreshed and rewritten
+    let config = if args.list_formats {
+        config
+    } else {
+        login::refresh_if_needed(config, CONFIG_PATH, &logger).await
+    };
+    let cookies = prepare_cookies(&args, &config, &logger).await?;
+    let crawler = Crawler::new(cookies, &logger);
+    let account = check_account(&crawler, &logger).await;
+    // listing formats does not mux, so it needs neither a working muxer nor the Ctrl-C cleanup
+    let muxer: Box<dyn Muxer> = if args.list_formats {
+        Box::new(NativeMuxer)
+    } else {
+        let ffmpeg = FfmpegOptions {
+            path: args.ffmpeg.clone().or_else(|| config.ffmpeg.clone()),
+            extra_args: [config.ffmpeg_extra_args.clone(), args.ffmpeg_args.clone()].concat(),
+        };
+        workdir::handle_ctrlc()?;
+        create_muxer(args.muxer.or(config.muxer), ffmpeg, &logger)?
+    };
+    let downloader = Downloader::new(
+        &logger,
+        &crawler,
+        muxer.as_ref(),
+        DownloadOptions {
+            quality: args.quality,
+            format,
+            preferences: VideoPreferences {
+                prefer_high_fps: !args.no_high_fps,
+                allow_hdr: args.allow_hdr,
+                allow_dolby_vision: args.allow_dolby_vision,
+            },
+            on_preview: args.on_preview,
+            output_dir: args.output_dir.clone(),
+            output,
+            sanitizer: Sanitizer {
+                profile: args.filename_profile,
+                max_bytes: args.filename_max_bytes,
+            },
+            work_dir: args
+                .work_dir
+                .clone()
+                .or_else(|| config.work_dir.clone())
+                .unwrap_or_else(workdir::default_root),
+            on_exists: args.on_exists,
+            archive,
+            upgrade: args.upgrade,
+            container: args.container,
+            no_merge: args.no_merge,
+            keep_streams: args.keep_streams,
+            account,
+        },
+    );
 
     let mut failed_ids = Vec::new();
 
     for video_id in args.video_ids {
-        let download_result = downloader.download(&video_id).await;
+        let download_result = if args.list_formats {
+            downloader
+                .list_formats(&video_id, args.json, &mut std::io::stdout())
+                .await
+        } else {
+            downloader.download(&video_id).await
+        };
         if let Err(e) = download_result {
             logger.fatal(&format!("failed to download '{}'", video_id));
             logger.fatal(&format!("{}", e));
@@ -77,7 +291,7 @@ async fn main_inner() -> Result<()> {
         }
     }
 
-    if failed_ids.len() > 0 {
+    if !failed_ids.is_empty() {
         Err(anyhow::anyhow!(
             "failed to download: {}",
             failed_ids.join(", ")
@@ -94,91 +308,14 @@ async fn main() {
 
 #[cfg(test)]
 mod tests {
-    use std::fs;
+    use clap::Parser;
 
-    use tempdir::TempDir;
-
-    use crate::logger::Logger;
-    use crate::{read_config, Config};
-
-    #[test]
-    fn read_config_config_not_exist() {
-        let temp_dir = TempDir::new("read_config").unwrap();
-        let temp_file = temp_dir
-            .path()
-            .join("non_existing")
-            .to_str()
-            .unwrap()
-            .to_owned();
-        let logger = Logger::new(0);
-        let config = read_config(&temp_file, &logger);
-        assert_eq!(
-            config,
-            Config {
-                sess_data: "".to_owned(),
-            },
-            "sess_data should be parsed to '' if no config is presented"
-        );
-    }
-
-    #[test]
-    fn read_config_missing_sess_data() {
-        let temp_dir = TempDir::new("read_config").unwrap();
-        let temp_file = temp_dir
-            .path()
-            .join("missing_sess_data")
-            .to_str()
-            .unwrap()
-            .to_owned();
-        let config_content = "{}";
-        fs::write(&temp_file, config_content).expect("Unable to write file");
-        let logger = Logger::new(0);
-        let config = read_config(&temp_file, &logger);
-        assert_eq!(
-            config,
-            Config {
-                sess_data: "".to_owned(),
-            },
-            "sess_data should be parsed to '' if config does not contain SESSDATA"
-        );
-    }
+    use super::Args;
+    use crate::download::OnExists;
 
     #[test]
-    fn read_config_wrong_sess_type() {
-        let temp_dir = TempDir::new("read_config").unwrap();
-        let temp_file = temp_dir
-            .path()
-            .join("wrong_sess_type")
-            .to_str()
-            .unwrap()
-            .to_owned();
-        let config_content = "{ \"SESSDATA\": 2 }";
-        fs::write(&temp_file, config_content).expect("Unable to write file");
-        let logger = Logger::new(0);
-        let config = read_config(&temp_file, &logger);
-        assert_eq!(
-            config,
-            Config {
-                sess_data: "".to_owned(),
-            },
-            "sess_data should be parsed to '' if SESSDATA is not a string"
-        );
-    }
-
-    #[test]
-    fn read_config_success() {
-        let temp_dir = TempDir::new("read_config").unwrap();
-        let temp_file = temp_dir.path().join("success").to_str().unwrap().to_owned();
-        let config_content = "{ \"SESSDATA\": \"2\" }";
-        fs::write(&temp_file, config_content).expect("Unable to write file");
-        let logger = Logger::new(0);
-        let config = read_config(&temp_file, &logger);
-        assert_eq!(
-            config,
-            Config {
-                sess_data: "2".to_owned(),
-            },
-            "sess_data should be parsed correctly"
-        );
+    fn skip_existing_by_default() {
+        let args = Args::try_parse_from(["bilibili-downloader", "BV17x411w7KC"]).unwrap();
+        assert_eq!(args.on_exists, OnExists::Skip);
     }
 }
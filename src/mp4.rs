@@ -0,0 +1,657 @@
+//! Combines the fragmented MP4 video and audio tracks of DASH into one fragmented MP4, without
+//! re-encoding and without ffmpeg.
+//!
+//! Each input has `ftyp`, `moov` with a single `trak`, `sidx` and then `moof` + `mdat` fragments.
+//! The output has the `moov` of both tracks with renumbered track ids, followed by the fragments
+//! of both inputs ordered by their decode time. `sidx` is dropped as its offsets are no longer
+//! valid. `mehd` is kept with the duration of the longer track.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+
+type FourCC = [u8; 4];
+
+/// Boxes whose payload is a list of boxes. Others are kept as opaque data.
+const CONTAINERS: [&FourCC; 6] = [b"moov", b"trak", b"mdia", b"mvex", b"moof", b"traf"];
+
+/// `tfhd` flag for the presence of base_data_offset, which is an absolute file offset.
+const TFHD_BASE_DATA_OFFSET: u32 = 0x000001;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Content {
+    Data(Vec<u8>),
+    Children(Vec<Mp4Box>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Mp4Box {
+    kind: FourCC,
+    content: Content,
+}
+
+/// Returns the type, the header size and the total size of the box at the start of `data`.
+fn parse_header(data: &[u8]) -> Result<(FourCC, usize, usize)> {
+    if data.len() < 8 {
+        return Err(anyhow!("truncated box header"));
+    }
+    let kind: FourCC = data[4..8].try_into().unwrap();
+    let (header, size) = match u32::from_be_bytes(data[0..4].try_into().unwrap()) {
+        0 => (8, data.len()),
+        1 => {
+            if data.len() < 16 {
+                return Err(anyhow!("truncated box header"));
+            }
+            (
+                16,
+                u64::from_be_bytes(data[8..16].try_into().unwrap()) as usize,
+            )
+        }
+        size => (8, size as usize),
+    };
+    if size < header || size > data.len() {
+        return Err(anyhow!(
+            "invalid size {size} of box '{}'",
+            String::from_utf8_lossy(&kind)
+        ));
+    }
+    Ok((kind, header, size))
+}
+
+fn parse_boxes(mut data: &[u8]) -> Result<Vec<Mp4Box>> {
+    let mut boxes = Vec::new();
+    while !data.is_empty() {
+        let (kind, header, size) = parse_header(data)?;
+        let payload = &data[header..size];
+        let content = if CONTAINERS.contains(&&kind) {
+            Content::Children(parse_boxes(payload)?)
+        } else {
+            Content::Data(payload.to_vec())
+        };
+        boxes.push(Mp4Box { kind, content });
+        data = &data[size..];
+    }
+    Ok(boxes)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32> {
+    data.get(pos..pos + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| anyhow!("truncated box"))
+}
+
+fn read_u64(data: &[u8], pos: usize) -> Result<u64> {
+    data.get(pos..pos + 8)
+        .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| anyhow!("truncated box"))
+}
+
+fn write_u32(data: &mut [u8], pos: usize, value: u32) -> Result<()> {
+    data.get_mut(pos..pos + 4)
+        .ok_or_else(|| anyhow!("truncated box"))?
+        .copy_from_slice(&value.to_be_bytes());
+    Ok(())
+}
+
+fn write_u64(data: &mut [u8], pos: usize, value: u64) -> Result<()> {
+    data.get_mut(pos..pos + 8)
+        .ok_or_else(|| anyhow!("truncated box"))?
+        .copy_from_slice(&value.to_be_bytes());
+    Ok(())
+}
+
+/// Position of the field after creation_time and modification_time in `tkhd` and `mdhd`.
+fn after_times(data: &[u8]) -> usize {
+    if data.first() == Some(&1) {
+        20
+    } else {
+        12
+    }
+}
+
+impl Mp4Box {
+    fn children(&self) -> &[Mp4Box] {
+        match &self.content {
+            Content::Children(children) => children,
+            Content::Data(_) => &[],
+        }
+    }
+
+    fn children_mut(&mut self) -> &mut [Mp4Box] {
+        match &mut self.content {
+            Content::Children(children) => children,
+            Content::Data(_) => &mut [],
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        match &self.content {
+            Content::Data(data) => data,
+            Content::Children(_) => &[],
+        }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        match &mut self.content {
+            Content::Data(data) => data,
+            Content::Children(_) => &mut [],
+        }
+    }
+
+    fn child(&self, kind: &FourCC) -> Option<&Mp4Box> {
+        self.children().iter().find(|b| &b.kind == kind)
+    }
+
+    fn child_mut(&mut self, kind: &FourCC) -> Option<&mut Mp4Box> {
+        self.children_mut().iter_mut().find(|b| &b.kind == kind)
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        let mut payload = Vec::new();
+        match &self.content {
+            Content::Data(data) => payload.extend_from_slice(data),
+            Content::Children(children) => {
+                for child in children {
+                    child.write_to(&mut payload);
+                }
+            }
+        }
+        out.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+        out.extend_from_slice(&self.kind);
+        out.extend_from_slice(&payload);
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes);
+        bytes
+    }
+}
+
+/// A top level box in the input file, the payload is read on demand.
+#[derive(Clone)]
+struct BoxLocation {
+    kind: FourCC,
+    offset: u64,
+    size: u64,
+}
+
+struct Fragment {
+    input: usize,
+    moof: BoxLocation,
+    mdats: Vec<BoxLocation>,
+    /// Decode time in seconds of the first sample.
+    time: f64,
+}
+
+struct Input {
+    file: File,
+    boxes: Vec<BoxLocation>,
+    trak: Mp4Box,
+    trex: Option<Mp4Box>,
+    moov: Mp4Box,
+    timescale: u32,
+}
+
+fn index_boxes(file: &mut File) -> Result<Vec<BoxLocation>> {
+    let len = file.metadata()?.len();
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header[..8])?;
+        let kind: FourCC = header[4..8].try_into().unwrap();
+        let (header_size, size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            0 => (8, len - offset),
+            1 => {
+                file.read_exact(&mut header[8..])?;
+                (16, u64::from_be_bytes(header[8..].try_into().unwrap()))
+            }
+            size => (8, size.into()),
+        };
+        // checked, as a corrupt size could overflow the end offset
+        let end = offset.checked_add(size).filter(|end| *end <= len);
+        let (Some(end), true) = (end, size >= header_size) else {
+            return Err(anyhow!(
+                "invalid size {size} of box '{}'",
+                String::from_utf8_lossy(&kind)
+            ));
+        };
+        boxes.push(BoxLocation { kind, offset, size });
+        offset = end;
+    }
+    Ok(boxes)
+}
+
+fn read_box(file: &mut File, location: &BoxLocation) -> Result<Mp4Box> {
+    file.seek(SeekFrom::Start(location.offset))?;
+    let mut bytes = vec![0; location.size as usize];
+    file.read_exact(&mut bytes)?;
+    parse_boxes(&bytes)?
+        .pop()
+        .ok_or_else(|| anyhow!("empty box"))
+}
+
+impl Input {
+    fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let boxes = index_boxes(&mut file)?;
+        let moov_location = boxes
+            .iter()
+            .find(|b| &b.kind == b"moov")
+            .ok_or_else(|| anyhow!("moov not found in {}", path.display()))?;
+        let moov = read_box(&mut file, moov_location)?;
+        let traks: Vec<&Mp4Box> = moov
+            .children()
+            .iter()
+            .filter(|b| &b.kind == b"trak")
+            .collect();
+        let [trak] = traks[..] else {
+            return Err(anyhow!(
+                "expect one track in {}, found {}",
+                path.display(),
+                traks.len()
+            ));
+        };
+        let mdhd = trak
+            .child(b"mdia")
+            .and_then(|mdia| mdia.child(b"mdhd"))
+            .ok_or_else(|| anyhow!("mdhd not found in {}", path.display()))?;
+        let timescale = read_u32(mdhd.data(), after_times(mdhd.data()))?;
+        let trex = moov
+            .child(b"mvex")
+            .and_then(|mvex| mvex.child(b"trex"))
+            .cloned();
+        Ok(Input {
+            file,
+            boxes,
+            trak: trak.clone(),
+            trex,
+            moov,
+            timescale: timescale.max(1),
+        })
+    }
+
+    fn fragments(&mut self, input: usize) -> Result<Vec<Fragment>> {
+        let mut fragments: Vec<Fragment> = Vec::new();
+        for location in std::mem::take(&mut self.boxes) {
+            match &location.kind {
+                b"moof" => {
+                    let moof = read_box(&mut self.file, &location)?;
+                    let decode_time = match moof.child(b"traf").and_then(|t| t.child(b"tfdt")) {
+                        Some(tfdt) if tfdt.data().first() == Some(&1) => read_u64(tfdt.data(), 4)?,
+                        Some(tfdt) => read_u32(tfdt.data(), 4)?.into(),
+                        // keep the order of fragments without tfdt
+                        None => 0,
+                    };
+                    let time = match fragments.last() {
+                        Some(last) if decode_time == 0 => last.time,
+                        _ => decode_time as f64 / self.timescale as f64,
+                    };
+                    fragments.push(Fragment {
+                        input,
+                        moof: location,
+                        mdats: Vec::new(),
+                        time,
+                    });
+                }
+                b"mdat" => match fragments.last_mut() {
+                    Some(fragment) => fragment.mdats.push(location),
+                    None => return Err(anyhow!("mdat before any moof")),
+                },
+                // ftyp, moov, sidx, styp, free, mfra...
+                _ => {}
+            }
+        }
+        Ok(fragments)
+    }
+}
+
+fn set_track_id(trak: &mut Mp4Box, track_id: u32) -> Result<()> {
+    let tkhd = trak
+        .child_mut(b"tkhd")
+        .ok_or_else(|| anyhow!("tkhd not found"))?;
+    let pos = after_times(tkhd.data());
+    write_u32(tkhd.data_mut(), pos, track_id)
+}
+
+/// `fragment_duration` of `mehd` in seconds, `None` if the input does not have it.
+fn fragment_duration(moov: &Mp4Box) -> Result<Option<f64>> {
+    let Some(mehd) = moov.child(b"mvex").and_then(|mvex| mvex.child(b"mehd")) else {
+        return Ok(None);
+    };
+    let mvhd = moov
+        .child(b"mvhd")
+        .ok_or_else(|| anyhow!("mvhd not found"))?;
+    let timescale = read_u32(mvhd.data(), after_times(mvhd.data()))?.max(1);
+    let duration = if mehd.data().first() == Some(&1) {
+        read_u64(mehd.data(), 4)?
+    } else {
+        read_u32(mehd.data(), 4)?.into()
+    };
+    Ok(Some(duration as f64 / timescale as f64))
+}
+
+/// The `moov` of the output, with the tracks of all inputs numbered from 1.
+fn merge_moov(inputs: &[Input]) -> Result<Mp4Box> {
+    // the longest track, converted to the timescale of the output which is the one of the video
+    let mut duration: Option<f64> = None;
+    for input in inputs {
+        if let Some(input_duration) = fragment_duration(&input.moov)? {
+            duration = Some(duration.map_or(input_duration, |d| d.max(input_duration)));
+        }
+    }
+
+    let mut moov = inputs[0].moov.clone();
+    let Content::Children(children) = &mut moov.content else {
+        unreachable!("moov is a container");
+    };
+    children.retain(|b| &b.kind != b"trak" && &b.kind != b"mvex");
+    let mvhd = children
+        .iter_mut()
+        .find(|b| &b.kind == b"mvhd")
+        .ok_or_else(|| anyhow!("mvhd not found"))?;
+    let next_track_id_pos = mvhd
+        .data()
+        .len()
+        .checked_sub(4)
+        .ok_or_else(|| anyhow!("truncated mvhd"))?;
+    write_u32(mvhd.data_mut(), next_track_id_pos, inputs.len() as u32 + 1)?;
+    let timescale = read_u32(mvhd.data(), after_times(mvhd.data()))?.max(1);
+
+    let mut mvex = Vec::new();
+    if let Some(duration) = duration {
+        // version 1 with a 64-bit fragment_duration
+        let mut mehd = vec![1, 0, 0, 0];
+        mehd.extend_from_slice(&((duration * timescale as f64).round() as u64).to_be_bytes());
+        mvex.push(Mp4Box {
+            kind: *b"mehd",
+            content: Content::Data(mehd),
+        });
+    }
+    for (idx, input) in inputs.iter().enumerate() {
+        let track_id = idx as u32 + 1;
+        let mut trak = input.trak.clone();
+        set_track_id(&mut trak, track_id)?;
+        children.push(trak);
+        if let Some(trex) = &input.trex {
+            let mut trex = trex.clone();
+            write_u32(trex.data_mut(), 4, track_id)?;
+            mvex.push(trex);
+        }
+    }
+    children.push(Mp4Box {
+        kind: *b"mvex",
+        content: Content::Children(mvex),
+    });
+    Ok(moov)
+}
+
+/// Renumbers the fragment for the output, `offset_delta` is how far the moof moves in the output.
+fn patch_moof(
+    moof: &mut Mp4Box,
+    sequence_number: u32,
+    track_id: u32,
+    offset_delta: i64,
+) -> Result<()> {
+    if let Some(mfhd) = moof.child_mut(b"mfhd") {
+        write_u32(mfhd.data_mut(), 4, sequence_number)?;
+    }
+    for traf in moof
+        .children_mut()
+        .iter_mut()
+        .filter(|b| &b.kind == b"traf")
+    {
+        let Some(tfhd) = traf.child_mut(b"tfhd") else {
+            continue;
+        };
+        let flags = read_u32(tfhd.data(), 0)? & 0x00ff_ffff;
+        write_u32(tfhd.data_mut(), 4, track_id)?;
+        if flags & TFHD_BASE_DATA_OFFSET != 0 {
+            let base_data_offset = read_u64(tfhd.data(), 8)?;
+            let base_data_offset = base_data_offset
+                .checked_add_signed(offset_delta)
+                .ok_or_else(|| anyhow!("invalid base_data_offset"))?;
+            write_u64(tfhd.data_mut(), 8, base_data_offset)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remuxes the fragmented MP4 `video` and `audio` into `output`.
+pub fn remux(video: &Path, audio: &Path, output: &Path) -> Result<()> {
+    let mut inputs = [Input::open(video)?, Input::open(audio)?];
+    let ftyp_location = inputs[0]
+        .boxes
+        .iter()
+        .find(|b| &b.kind == b"ftyp")
+        .cloned()
+        .ok_or_else(|| anyhow!("ftyp not found in {}", video.display()))?;
+    let ftyp = read_box(&mut inputs[0].file, &ftyp_location)?;
+    let moov = merge_moov(&inputs)?;
+
+    let mut fragments = Vec::new();
+    for (idx, input) in inputs.iter_mut().enumerate() {
+        fragments.extend(input.fragments(idx)?);
+    }
+    // stable, so the video goes first if both start at the same time
+    fragments.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    let mut position = 0u64;
+    for header in [ftyp.to_bytes(), moov.to_bytes()] {
+        writer.write_all(&header)?;
+        position += header.len() as u64;
+    }
+    for (idx, fragment) in fragments.iter().enumerate() {
+        let input = &mut inputs[fragment.input];
+        let mut moof = read_box(&mut input.file, &fragment.moof)?;
+        patch_moof(
+            &mut moof,
+            idx as u32 + 1,
+            fragment.input as u32 + 1,
+            position as i64 - fragment.moof.offset as i64,
+        )?;
+        let moof = moof.to_bytes();
+        // data offsets in trun are relative to the moof, so its size must not change
+        if moof.len() as u64 != fragment.moof.size {
+            return Err(anyhow!("unsupported moof with 64-bit box sizes"));
+        }
+        writer.write_all(&moof)?;
+        position += moof.len() as u64;
+        for mdat in &fragment.mdats {
+            input.file.seek(SeekFrom::Start(mdat.offset))?;
+            let copied = io::copy(&mut (&mut input.file).take(mdat.size), &mut writer)?;
+            if copied != mdat.size {
+                return Err(anyhow!("unexpected end of file in mdat"));
+            }
+            position += copied;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tempdir::TempDir;
+
+    use super::{parse_boxes, read_u32, read_u64, remux, Mp4Box};
+
+    fn make_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32 + 8).to_be_bytes().to_vec();
+        bytes.extend_from_slice(kind);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    /// A full box payload of version 0 with `u32` fields.
+    fn full_box(flags: u32, fields: &[u32], len: usize) -> Vec<u8> {
+        let mut payload = flags.to_be_bytes().to_vec();
+        for field in fields {
+            payload.extend_from_slice(&field.to_be_bytes());
+        }
+        payload.resize(len, 0);
+        payload
+    }
+
+    /// A single track fMP4 like the DASH streams, with a fragment of one sample for each
+    /// `(decode_time, data)`.
+    fn track_file(
+        path: &PathBuf,
+        timescale: u32,
+        fragment_duration: u32,
+        fragments: &[(u64, &[u8])],
+    ) {
+        let mut bytes = make_box(b"ftyp", b"iso5\0\0\0\x01iso5iso6mp41");
+        let mut mvhd = full_box(0, &[0, 0, 1000, 0], 100);
+        mvhd[96..].copy_from_slice(&2u32.to_be_bytes());
+        let tkhd = full_box(0x3, &[0, 0, 1], 84);
+        let mdhd = full_box(0, &[0, 0, timescale], 24);
+        let trak = make_box(
+            b"trak",
+            &[
+                make_box(b"tkhd", &tkhd),
+                make_box(b"mdia", &make_box(b"mdhd", &mdhd)),
+            ]
+            .concat(),
+        );
+        let mvex = [
+            make_box(b"mehd", &full_box(0, &[fragment_duration], 8)),
+            make_box(b"trex", &full_box(0, &[1, 1], 24)),
+        ]
+        .concat();
+        let moov = [make_box(b"mvhd", &mvhd), trak, make_box(b"mvex", &mvex)].concat();
+        bytes.extend(make_box(b"moov", &moov));
+        bytes.extend(make_box(b"sidx", &[0; 32]));
+        for (idx, (decode_time, data)) in fragments.iter().enumerate() {
+            let mut tfdt = 0x0100_0000u32.to_be_bytes().to_vec();
+            tfdt.extend_from_slice(&decode_time.to_be_bytes());
+            // moof(8) mfhd(16) traf(8) tfhd(16) tfdt(20) trun(20)
+            let data_offset = 8 + 16 + 8 + 16 + 20 + 20 + 8;
+            let traf = [
+                make_box(b"tfhd", &full_box(0x020000, &[1], 8)),
+                make_box(b"tfdt", &tfdt),
+                make_box(b"trun", &full_box(0x1, &[1, data_offset], 12)),
+            ]
+            .concat();
+            let moof = [
+                make_box(b"mfhd", &full_box(0, &[idx as u32 + 1], 8)),
+                make_box(b"traf", &traf),
+            ]
+            .concat();
+            bytes.extend(make_box(b"moof", &moof));
+            bytes.extend(make_box(b"mdat", data));
+        }
+        fs::write(path, bytes).unwrap();
+    }
+
+    fn child<'a>(parent: &'a Mp4Box, path: &[&[u8; 4]]) -> &'a Mp4Box {
+        path.iter().fold(parent, |b, kind| b.child(kind).unwrap())
+    }
+
+    #[test]
+    fn remux_video_and_audio() {
+        let dir = TempDir::new("mp4").unwrap();
+        let video = dir.path().join("video.mp4");
+        let audio = dir.path().join("audio.mp4");
+        let output = dir.path().join("output.mp4");
+        track_file(&video, 1000, 4000, &[(0, b"v0"), (2000, b"v1")]);
+        track_file(
+            &audio,
+            44100,
+            3000,
+            &[(0, b"a0"), (44100, b"a1"), (88200, b"a2")],
+        );
+
+        remux(&video, &audio, &output).unwrap();
+
+        let bytes = fs::read(&output).unwrap();
+        let boxes = parse_boxes(&bytes).unwrap();
+        let kinds: Vec<&[u8]> = boxes.iter().map(|b| &b.kind[..]).collect();
+        assert_eq!(kinds[..2], [b"ftyp", b"moov"]);
+        assert!(!kinds.contains(&&b"sidx"[..]), "sidx should be dropped");
+
+        let moov = &boxes[1];
+        let track_ids: Vec<u32> = moov
+            .children()
+            .iter()
+            .filter(|b| &b.kind == b"trak")
+            .map(|trak| read_u32(child(trak, &[b"tkhd"]).data(), 12).unwrap())
+            .collect();
+        assert_eq!(track_ids, [1, 2]);
+        let trex_ids: Vec<u32> = child(moov, &[b"mvex"])
+            .children()
+            .iter()
+            .filter(|b| &b.kind == b"trex")
+            .map(|trex| read_u32(trex.data(), 4).unwrap())
+            .collect();
+        assert_eq!(trex_ids, [1, 2]);
+        let mehd = child(moov, &[b"mvex", b"mehd"]).data();
+        assert_eq!(
+            read_u64(mehd, 4).unwrap(),
+            4000,
+            "duration of the longer track"
+        );
+        let mvhd = child(moov, &[b"mvhd"]).data();
+        assert_eq!(read_u32(mvhd, 96).unwrap(), 3, "next_track_ID");
+
+        // fragments are interleaved by time, and trun data offsets still point to the samples
+        let mut offset = boxes[..2].iter().map(|b| b.to_bytes().len()).sum::<usize>();
+        let mut samples = Vec::new();
+        for (idx, moof) in boxes[2..].iter().step_by(2).enumerate() {
+            assert_eq!(&moof.kind, b"moof");
+            let sequence_number = read_u32(child(moof, &[b"mfhd"]).data(), 4).unwrap();
+            assert_eq!(sequence_number, idx as u32 + 1);
+            let track_id = read_u32(child(moof, &[b"traf", b"tfhd"]).data(), 4).unwrap();
+            let decode_time = read_u64(child(moof, &[b"traf", b"tfdt"]).data(), 4).unwrap();
+            let data_offset = read_u32(child(moof, &[b"traf", b"trun"]).data(), 8).unwrap();
+            let sample_start = offset + data_offset as usize;
+            samples.push((
+                track_id,
+                decode_time,
+                String::from_utf8_lossy(&bytes[sample_start..sample_start + 2]).into_owned(),
+            ));
+            offset += moof.to_bytes().len() + boxes[2 + idx * 2 + 1].to_bytes().len();
+        }
+        let expected = [
+            (1, 0, "v0"),
+            (2, 0, "a0"),
+            (2, 44100, "a1"),
+            (1, 2000, "v1"),
+            (2, 88200, "a2"),
+        ];
+        assert_eq!(
+            samples,
+            expected
+                .iter()
+                .map(|(id, time, data)| (*id, *time, data.to_string()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reject_invalid_input() {
+        let dir = TempDir::new("mp4").unwrap();
+        let video = dir.path().join("video.mp4");
+        fs::write(&video, make_box(b"ftyp", b"iso5")).unwrap();
+        let error = remux(&video, &video, &dir.path().join("output.mp4")).unwrap_err();
+        assert!(error.to_string().contains("moov not found"));
+
+        // a 64-bit size that would overflow the end offset
+        let mut bytes = make_box(b"ftyp", b"iso5");
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        bytes.extend_from_slice(b"mdat");
+        bytes.extend_from_slice(&u64::MAX.to_be_bytes());
+        fs::write(&video, bytes).unwrap();
+        let error = remux(&video, &video, &dir.path().join("output.mp4")).unwrap_err();
+        assert!(error.to_string().contains("invalid size"));
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use super::{Capabilities, Container, Muxer};
+use crate::logger::Logger;
+
+/// `-progress` needs ffmpeg 4.
+const MIN_VERSION: (u32, u32) = (4, 0);
+/// Older versions only write FLAC into MP4 with `-strict experimental`.
+const FLAC_IN_MP4_VERSION: (u32, u32) = (6, 0);
+/// How often the merge progress is logged.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct FfmpegOptions {
+    /// Looked up in PATH if not set.
+    pub path: Option<PathBuf>,
+    /// Added before the output file, e.g. `-movflags +faststart`.
+    pub extra_args: Vec<String>,
+}
+
+/// Parses the version from the first line of `ffmpeg -version`, e.g. `ffmpeg version 6.1.1-3ubuntu5`
+/// or `ffmpeg version n7.0`. Returns `None` for git builds like `ffmpeg version N-113348-g0a5813fc68`.
+fn parse_version(output: &str) -> Option<(u32, u32)> {
+    let version = output
+        .lines()
+        .next()?
+        .strip_prefix("ffmpeg version ")?
+        .trim_start_matches('n');
+    let mut numbers = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse::<u32>());
+    let major = numbers.next()?.ok()?;
+    let minor = numbers.next().and_then(|n| n.ok()).unwrap_or(0);
+    Some((major, minor))
+}
+
+/// Reads the `key=value` blocks of `-progress`, each ends with `progress=continue|end`. Calls
+/// `report` with the out time and speed of each block, and whether it is the last one.
+fn read_progress(stdout: impl Read, mut report: impl FnMut(&str, &str, bool)) {
+    let mut out_time = String::new();
+    let mut speed = String::new();
+    for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+        match line.split_once('=') {
+            Some(("out_time", value)) => out_time = value.to_owned(),
+            Some(("speed", value)) => speed = value.trim().to_owned(),
+            Some(("progress", value)) => report(&out_time, &speed, value == "end"),
+            _ => {}
+        }
+    }
+}
+
+pub struct FfmpegMuxer<'a> {
+    logger: &'a Logger,
+    program: PathBuf,
+    extra_args: Vec<String>,
+    /// `None` for git builds, which are assumed to be recent.
+    version: Option<(u32, u32)>,
+}
+
+impl<'a> FfmpegMuxer<'a> {
+    /// Checks that ffmpeg exists and is recent enough.
+    pub fn new(options: FfmpegOptions, logger: &'a Logger) -> Result<Self> {
+        let program = options.path.unwrap_or_else(|| PathBuf::from("ffmpeg"));
+        let output = Command::new(&program)
+            .arg("-version")
+            .output()
+            .map_err(|e| {
+                anyhow!(
+                "failed to run '{}': {e}. Install ffmpeg, set its path with --ffmpeg or \"ffmpeg\" \
+                 in config.json, or use --muxer native",
+                program.display()
+            )
+            })?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let version = parse_version(&stdout);
+        match version {
+            Some(version) if version < MIN_VERSION => {
+                return Err(anyhow!(
+                    "ffmpeg {}.{} is too old, {}.{} or later is required: {}",
+                    version.0,
+                    version.1,
+                    MIN_VERSION.0,
+                    MIN_VERSION.1,
+                    program.display()
+                ));
+            }
+            Some(version) => logger.debug(&format!("ffmpeg version {}.{}", version.0, version.1)),
+            None => logger.debug(&format!(
+                "unknown ffmpeg version: {}",
+                stdout.lines().next().unwrap_or_default()
+            )),
+        }
+        Ok(FfmpegMuxer {
+            logger,
+            program,
+            extra_args: options.extra_args,
+            version,
+        })
+    }
+
+    /// Logs the progress every `PROGRESS_INTERVAL` and when done.
+    fn log_progress(&self, stdout: impl Read) {
+        let mut last_logged: Option<Instant> = None;
+        read_progress(stdout, |out_time, speed, end| {
+            if end || last_logged.is_none_or(|at| at.elapsed() >= PROGRESS_INTERVAL) {
+                self.logger
+                    .info(&format!("合并进度: {out_time}，速度: {speed}"));
+                last_logged = Some(Instant::now());
+            }
+        });
+    }
+}
+
+impl Muxer for FfmpegMuxer<'_> {
+    fn name(&self) -> &'static str {
+        "ffmpeg"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            containers: &[Container::Mp4, Container::Mkv, Container::Mov],
+            subtitles: true,
+            cover_art: true,
+            chapters: true,
+            flac_in_mp4: self.version.is_none_or(|v| v >= FLAC_IN_MP4_VERSION),
+        }
+    }
+
+    fn mux(&self, video: &Path, audio: &Path, output: &Path, container: Container) -> Result<()> {
+        let format = match container {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "matroska",
+            Container::Mov => "mov",
+        };
+        let mut child = Command::new(&self.program)
+            .arg("-nostdin")
+            .arg("-y")
+            .args(["-loglevel", "error", "-nostats", "-progress", "pipe:1"])
+            .arg("-i")
+            .arg(video)
+            .arg("-i")
+            .arg(audio)
+            .args(["-map", "0:v", "-map", "1:a", "-c", "copy"])
+            .args(&self.extra_args)
+            .arg("-f")
+            .arg(format)
+            .arg(output)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("failed to run {}: {e}", self.program.display()))?;
+
+        // read stderr in another thread, so that neither pipe blocks ffmpeg
+        let mut stderr = child.stderr.take().unwrap();
+        let stderr_reader = thread::spawn(move || {
+            let mut content = String::new();
+            let _ = stderr.read_to_string(&mut content);
+            content
+        });
+        self.log_progress(child.stdout.take().unwrap());
+        let status = child.wait()?;
+        let stderr = stderr_reader.join().unwrap_or_default();
+        if !status.success() {
+            return Err(anyhow!("ffmpeg failed with {status}: {stderr}"));
+        }
+        if !stderr.is_empty() {
+            self.logger.verbose(&format!("ffmpeg stderr: {stderr}"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_version, read_progress};
+
+    #[test]
+    fn parse_ffmpeg_version() {
+        let parse = |line: &str| parse_version(&format!("{line}\nbuilt with gcc 13"));
+        assert_eq!(
+            parse("ffmpeg version 6.1.1-3ubuntu5 Copyright (c) 2000-2023"),
+            Some((6, 1))
+        );
+        assert_eq!(parse("ffmpeg version n7.0 Copyright"), Some((7, 0)));
+        assert_eq!(parse("ffmpeg version 3.4.8"), Some((3, 4)));
+        assert_eq!(parse("ffmpeg version N-113348-g0a5813fc68"), None);
+        assert_eq!(parse("not ffmpeg"), None);
+    }
+
+    #[test]
+    fn read_progress_blocks() {
+        let stdout = "frame=0\nout_time=00:00:01.500000\nspeed=N/A\nprogress=continue\n\
+                      out_time_us=3000000\nout_time=00:00:03.000000\nspeed= 150x\nprogress=end\n";
+        let mut reports = Vec::new();
+        read_progress(stdout.as_bytes(), |out_time, speed, end| {
+            reports.push((out_time.to_owned(), speed.to_owned(), end))
+        });
+        assert_eq!(
+            reports,
+            vec![
+                ("00:00:01.500000".to_owned(), "N/A".to_owned(), false),
+                ("00:00:03.000000".to_owned(), "150x".to_owned(), true),
+            ]
+        );
+    }
+}
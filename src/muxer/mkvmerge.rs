@@ -0,0 +1,69 @@
+use std::{path::Path, process::Command};
+
+use anyhow::{anyhow, Result};
+
+use super::{check_output, Capabilities, Container, Muxer};
+use crate::logger::Logger;
+
+/// Muxes into Matroska with mkvmerge from MKVToolNix.
+pub struct MkvmergeMuxer<'a> {
+    logger: &'a Logger,
+}
+
+impl<'a> MkvmergeMuxer<'a> {
+    pub fn new(logger: &'a Logger) -> Self {
+        MkvmergeMuxer { logger }
+    }
+}
+
+impl Muxer for MkvmergeMuxer<'_> {
+    fn name(&self) -> &'static str {
+        "mkvmerge"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            containers: &[Container::Mkv],
+            subtitles: true,
+            cover_art: true,
+            chapters: true,
+            // only writes Matroska
+            flac_in_mp4: false,
+        }
+    }
+
+    fn mux(&self, video: &Path, audio: &Path, output: &Path, container: Container) -> Result<()> {
+        if container != Container::Mkv {
+            return Err(anyhow!("mkvmerge only supports mkv, got {container:?}"));
+        }
+        let result = Command::new("mkvmerge")
+            .arg("-o")
+            .arg(output)
+            .arg(video)
+            .arg(audio)
+            .output()
+            .map_err(|e| anyhow!("failed to run mkvmerge: {e}"))?;
+        // exit code 1 means success with warnings
+        if result.status.code() == Some(1) {
+            self.logger.warn(&format!(
+                "mkvmerge 警告: {}",
+                String::from_utf8_lossy(&result.stdout)
+            ));
+            return Ok(());
+        }
+        check_output("mkvmerge", result, self.logger)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MkvmergeMuxer;
+    use crate::{logger::Logger, muxer::Muxer};
+
+    #[test]
+    fn missing_only_flac_in_mp4() {
+        let logger = Logger::new(0);
+        let muxer = MkvmergeMuxer::new(&logger);
+        assert_eq!(muxer.capabilities().missing(), ["MP4 中的 FLAC"]);
+    }
+}
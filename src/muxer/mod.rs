@@ -0,0 +1,132 @@
+mod ffmpeg;
+mod mkvmerge;
+mod native;
+
+use std::{path::Path, process::Output};
+
+use anyhow::{anyhow, Result};
+#[cfg(test)]
+use mockall::automock;
+use serde::{Deserialize, Serialize};
+
+use crate::logger::Logger;
+pub use ffmpeg::{FfmpegMuxer, FfmpegOptions};
+pub use mkvmerge::MkvmergeMuxer;
+pub use native::NativeMuxer;
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    Mp4,
+    Mkv,
+    Mov,
+}
+
+impl Container {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "mkv",
+            Container::Mov => "mov",
+        }
+    }
+}
+
+/// Optional features of a muxer, the features a muxer lacks are skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Capabilities {
+    /// Supported containers, the first one is the default.
+    pub containers: &'static [Container],
+    pub subtitles: bool,
+    pub cover_art: bool,
+    pub chapters: bool,
+    pub flac_in_mp4: bool,
+}
+
+impl Capabilities {
+    /// Names of the unsupported features, for logging.
+    pub fn missing(&self) -> Vec<&'static str> {
+        [
+            (self.subtitles, "字幕"),
+            (self.cover_art, "封面"),
+            (self.chapters, "章节"),
+            (self.flac_in_mp4, "MP4 中的 FLAC"),
+        ]
+        .into_iter()
+        .filter(|(supported, _)| !supported)
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+/// Combines the downloaded video and audio streams into one file.
+#[cfg_attr(test, automock)]
+pub trait Muxer {
+    fn name(&self) -> &'static str;
+
+    fn capabilities(&self) -> Capabilities;
+
+    /// Copies the streams into `output` without re-encoding.
+    fn mux(&self, video: &Path, audio: &Path, output: &Path, container: Container) -> Result<()>;
+}
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MuxerKind {
+    Ffmpeg,
+    Mkvmerge,
+    Native,
+}
+
+/// Checks the exit code, and logs stdout and stderr of an external program.
+fn check_output(program: &str, output: Output, logger: &Logger) -> Result<()> {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let Some(exit_code) = output.status.code() else {
+        return Err(anyhow!(
+            "{program} exit without exit code, stdout: {stdout}, stderr: {stderr}"
+        ));
+    };
+    if exit_code != 0 {
+        return Err(anyhow!(
+            "{program} exit code: {exit_code}, stdout: {stdout}, stderr: {stderr}"
+        ));
+    }
+    logger.verbose(&format!("{program} stdout: {stdout}"));
+    logger.verbose(&format!("{program} stderr: {stderr}"));
+    Ok(())
+}
+
+/// Creates the muxer of `kind`. If not set, ffmpeg is used if it works, otherwise the native one,
+/// unless the ffmpeg path or arguments are set, which means ffmpeg is wanted.
+pub fn create_muxer<'a>(
+    kind: Option<MuxerKind>,
+    ffmpeg: FfmpegOptions,
+    logger: &'a Logger,
+) -> Result<Box<dyn Muxer + 'a>> {
+    let muxer: Box<dyn Muxer> = match kind {
+        Some(MuxerKind::Ffmpeg) => Box::new(FfmpegMuxer::new(ffmpeg, logger)?),
+        Some(MuxerKind::Mkvmerge) => Box::new(MkvmergeMuxer::new(logger)),
+        Some(MuxerKind::Native) => Box::new(NativeMuxer),
+        None if ffmpeg.path.is_some() || !ffmpeg.extra_args.is_empty() => {
+            Box::new(FfmpegMuxer::new(ffmpeg, logger)?)
+        }
+        None => match FfmpegMuxer::new(ffmpeg, logger) {
+            Ok(muxer) => Box::new(muxer),
+            Err(e) => {
+                logger.debug(&format!("{e}"));
+                logger.info("ffmpeg 不可用，使用内置的 MP4 合并");
+                Box::new(NativeMuxer)
+            }
+        },
+    };
+    let missing = muxer.capabilities().missing();
+    if !missing.is_empty() {
+        logger.debug(&format!(
+            "muxer {} does not support: {}",
+            muxer.name(),
+            missing.join(", ")
+        ));
+    }
+    Ok(muxer)
+}
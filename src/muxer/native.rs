@@ -0,0 +1,43 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use super::{Capabilities, Container, Muxer};
+use crate::mp4;
+
+/// Remuxes the fragmented MP4 streams in Rust, without any external program.
+pub struct NativeMuxer;
+
+impl Muxer for NativeMuxer {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            containers: &[Container::Mp4],
+            ..Default::default()
+        }
+    }
+
+    fn mux(&self, video: &Path, audio: &Path, output: &Path, container: Container) -> Result<()> {
+        if container != Container::Mp4 {
+            return Err(anyhow!("native muxer only supports mp4, got {container:?}"));
+        }
+        mp4::remux(video, audio, output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NativeMuxer;
+    use crate::muxer::Muxer;
+
+    #[test]
+    fn missing_all_features() {
+        assert_eq!(
+            NativeMuxer.capabilities().missing(),
+            ["字幕", "封面", "章节", "MP4 中的 FLAC"]
+        );
+    }
+}
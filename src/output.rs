@@ -0,0 +1,328 @@
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use chrono::{format::Item, format::StrftimeItems, Local, TimeZone};
+
+use crate::{bilibili::VideoMetadata, sanitize::Sanitizer};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Field {
+    Title,
+    Bvid,
+    Aid,
+    Cid,
+    Uploader,
+    Pubdate,
+    Page,
+    PartTitle,
+    Quality,
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Field {
+        field: Field,
+        format: Option<String>,
+    },
+}
+
+/// Output file name template like `{uploader}/{pubdate:%Y-%m-%d} {title}`, without extension.
+///
+/// `/` in the template creates subdirectories, while `/` in the field values are replaced.
+/// Use `{{` and `}}` for literal braces.
+#[derive(Debug, PartialEq)]
+pub struct OutputTemplate {
+    segments: Vec<Segment>,
+}
+
+/// Values of the template fields of a video.
+pub struct TemplateValues<'a> {
+    pub metadata: &'a VideoMetadata,
+    pub title: &'a str,
+    /// Name of the selected quality, e.g. `高清 1080P`.
+    pub quality: &'a str,
+}
+
+impl FromStr for Field {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        Ok(match name {
+            "title" => Field::Title,
+            "bvid" => Field::Bvid,
+            "aid" => Field::Aid,
+            "cid" => Field::Cid,
+            "uploader" => Field::Uploader,
+            "pubdate" => Field::Pubdate,
+            "page" => Field::Page,
+            "part_title" => Field::PartTitle,
+            "quality" => Field::Quality,
+            _ => return Err(anyhow!("unknown field '{{{name}}}'")),
+        })
+    }
+}
+
+fn parse_field(content: &str) -> Result<Segment> {
+    let (name, format) = match content.split_once(':') {
+        Some((name, format)) => (name, Some(format.to_owned())),
+        None => (content, None),
+    };
+    let field = name.trim().parse()?;
+    if let Some(format) = &format {
+        if field != Field::Pubdate {
+            return Err(anyhow!(
+                "only {{pubdate}} supports a format, got '{{{content}}}'"
+            ));
+        }
+        if StrftimeItems::new(format).any(|item| item == Item::Error) {
+            return Err(anyhow!("invalid date format '{format}'"));
+        }
+    }
+    Ok(Segment::Field { field, format })
+}
+
+impl FromStr for OutputTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(template: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut content = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        content.push(c);
+                    }
+                    if !closed {
+                        return Err(anyhow!("missing '}}' in output template '{template}'"));
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(
+                        parse_field(&content)
+                            .map_err(|e| anyhow!("invalid output template '{template}': {e}"))?,
+                    );
+                }
+                '}' => return Err(anyhow!("unmatched '}}' in output template '{template}'")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        if segments.is_empty() {
+            return Err(anyhow!("output template is empty"));
+        }
+        Ok(OutputTemplate { segments })
+    }
+}
+
+impl Default for OutputTemplate {
+    fn default() -> Self {
+        OutputTemplate {
+            segments: vec![Segment::Field {
+                field: Field::Title,
+                format: None,
+            }],
+        }
+    }
+}
+
+fn format_pubdate(timestamp: i64, format: &str) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time.format(format).to_string(),
+        None => String::new(),
+    }
+}
+
+impl OutputTemplate {
+    /// Whether the template only uses fields that are known from the video id, i.e. `{bvid}`
+    /// and `{page}`.
+    pub fn is_offline(&self) -> bool {
+        self.segments.iter().all(|segment| match segment {
+            Segment::Literal(_) => true,
+            Segment::Field { field, .. } => matches!(field, Field::Bvid | Field::Page),
+        })
+    }
+
+    fn field_value(field: Field, format: Option<&str>, values: &TemplateValues) -> String {
+        let metadata = values.metadata;
+        match field {
+            Field::Title => values.title.to_owned(),
+            Field::Bvid => metadata.bvid.clone(),
+            Field::Aid => metadata.aid.to_string(),
+            Field::Cid => metadata.cid.to_string(),
+            Field::Uploader => metadata.owner.name.clone(),
+            Field::Pubdate => format_pubdate(metadata.pubdate, format.unwrap_or("%Y-%m-%d")),
+            Field::Page => metadata.page.to_string(),
+            Field::PartTitle => metadata
+                .part()
+                .map(|part| part.part.clone())
+                .unwrap_or_default(),
+            Field::Quality => values.quality.to_owned(),
+        }
+    }
+
+    /// Renders the path relative to the output directory, returns the directory and the file
+    /// stem. The stem is not truncated, so that the caller can append the extension first.
+    pub fn render(&self, values: &TemplateValues, sanitizer: &Sanitizer) -> (PathBuf, String) {
+        let rendered: String = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.clone(),
+                Segment::Field { field, format } => {
+                    Self::field_value(*field, format.as_deref(), values).replace('/', "_")
+                }
+            })
+            .collect();
+        let mut components: Vec<&str> = rendered
+            .split('/')
+            .map(str::trim)
+            .filter(|component| !component.is_empty() && *component != "." && *component != "..")
+            .collect();
+        let stem = sanitizer.sanitize(components.pop().unwrap_or_default());
+        let dir = components
+            .into_iter()
+            .map(|component| sanitizer.file_name(component, ""))
+            .collect();
+        (dir, stem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{OutputTemplate, TemplateValues};
+    use crate::{
+        bilibili::VideoMetadata,
+        sanitize::{FilenameProfile, Sanitizer},
+    };
+
+    fn metadata() -> VideoMetadata {
+        serde_json::from_value(serde_json::json!({
+            "aid": 170001,
+            "bvid": "BV17x411w7KC",
+            "cid": 1002,
+            "title": "多P视频",
+            "desc": "",
+            "owner": {"name": "Cheer-up!"},
+            // 2011-11-09 15:35:33 UTC
+            "pubdate": 1320852933,
+            "duration": 0,
+            "page": 2,
+            "pages": [
+                {"cid": 1001, "page": 1, "part": "第一集"},
+                {"cid": 1002, "page": 2, "part": "第二集 a/b"}
+            ],
+            "stat": {},
+            "rights": {},
+            "ugc_season": null,
+            "subtitle": {},
+            "tags": []
+        }))
+        .unwrap()
+    }
+
+    const SANITIZER: Sanitizer = Sanitizer {
+        profile: FilenameProfile::Posix,
+        max_bytes: 255,
+    };
+
+    fn render(template: &str) -> PathBuf {
+        let metadata = metadata();
+        let (dir, stem) = template.parse::<OutputTemplate>().unwrap().render(
+            &TemplateValues {
+                metadata: &metadata,
+                title: "多P视频 P2 第二集 a/b",
+                quality: "高清 1080P",
+            },
+            &SANITIZER,
+        );
+        dir.join(stem)
+    }
+
+    #[test]
+    fn render_fields() {
+        assert_eq!(
+            OutputTemplate::default().render(
+                &TemplateValues {
+                    metadata: &metadata(),
+                    title: "a/b",
+                    quality: "",
+                },
+                &SANITIZER
+            ),
+            (PathBuf::new(), "a_b".to_owned())
+        );
+        assert_eq!(
+            render("{bvid}_{aid}_{cid} P{page} {part_title} [{quality}]"),
+            PathBuf::from("BV17x411w7KC_170001_1002 P2 第二集 a_b [高清 1080P]")
+        );
+        assert_eq!(
+            render("{uploader}/{pubdate:%Y}/{{{title}}}"),
+            ["Cheer-up!", "2011", "{多P视频 P2 第二集 a_b}"]
+                .iter()
+                .collect::<PathBuf>()
+        );
+        assert_eq!(render("../{bvid}"), PathBuf::from("BV17x411w7KC"));
+        let windows = Sanitizer {
+            profile: FilenameProfile::Windows,
+            max_bytes: 255,
+        };
+        let metadata = metadata();
+        let values = TemplateValues {
+            metadata: &metadata,
+            title: "a: b?",
+            quality: "",
+        };
+        let template: OutputTemplate = "aux/{title}.".parse().unwrap();
+        assert_eq!(
+            template.render(&values, &windows),
+            (PathBuf::from("aux_"), "a_ b_".to_owned())
+        );
+    }
+
+    #[test]
+    fn offline_fields() {
+        let offline = |template: &str| template.parse::<OutputTemplate>().unwrap().is_offline();
+        assert!(offline("videos/{bvid}_p{page}"));
+        assert!(!offline("{bvid} {title}"));
+        assert!(!offline("{bvid} [{quality}]"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for template in [
+            "",
+            "{unknown}",
+            "{title",
+            "title}",
+            "{bvid:%Y}",
+            "{pubdate:%Q}",
+        ] {
+            assert!(
+                template.parse::<OutputTemplate>().is_err(),
+                "'{template}' should be rejected"
+            );
+        }
+    }
+}
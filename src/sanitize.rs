@@ -0,0 +1,180 @@
+use std::path::{Path, PathBuf};
+
+/// Characters allowed in file names.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum FilenameProfile {
+    /// Only `/` and control characters are replaced.
+    Posix,
+    /// Also replaces `<>:"\|?*`, trailing dots and spaces, and reserved names like `CON`.
+    Windows,
+    /// Windows rules, and non-ASCII characters are replaced as well.
+    Ascii,
+}
+
+impl Default for FilenameProfile {
+    fn default() -> Self {
+        if cfg!(windows) {
+            FilenameProfile::Windows
+        } else {
+            FilenameProfile::Posix
+        }
+    }
+}
+
+const REPLACEMENT: char = '_';
+
+const WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Truncates `s` to at most `max_bytes` bytes without splitting a UTF-8 character.
+fn truncate_to_bytes(s: &str, max_bytes: usize) -> &str {
+    if s.len() <= max_bytes {
+        return s;
+    }
+    let mut end = max_bytes;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[derive(Debug, Clone)]
+pub struct Sanitizer {
+    pub profile: FilenameProfile,
+    /// Max length of each path component in bytes, 255 on most filesystems.
+    pub max_bytes: usize,
+}
+
+impl Sanitizer {
+    fn is_allowed(&self, c: char) -> bool {
+        if c == '/' || c.is_control() {
+            return false;
+        }
+        match self.profile {
+            FilenameProfile::Posix => true,
+            FilenameProfile::Windows => !"<>:\"\\|?*".contains(c),
+            FilenameProfile::Ascii => c.is_ascii() && !"<>:\"\\|?*".contains(c),
+        }
+    }
+
+    /// Replaces characters that are not allowed in a path component, without truncating.
+    pub fn sanitize(&self, name: &str) -> String {
+        let mut sanitized: String = name
+            .chars()
+            .map(|c| if self.is_allowed(c) { c } else { REPLACEMENT })
+            .collect();
+        if self.profile != FilenameProfile::Posix {
+            let trimmed_len = sanitized.trim_end_matches(['.', ' ']).len();
+            sanitized.truncate(trimmed_len);
+            let base = sanitized.split('.').next().unwrap_or_default();
+            if WINDOWS_RESERVED
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(base.trim_end()))
+            {
+                sanitized.insert(base.len(), REPLACEMENT);
+            }
+        }
+        match sanitized.as_str() {
+            "" | "." | ".." => REPLACEMENT.to_string(),
+            _ => sanitized,
+        }
+    }
+
+    /// Sanitizes `stem` and truncates it so that `stem + suffix` fits in the byte limit.
+    ///
+    /// `suffix` is kept as is, e.g. `.mp4` or ` (1).mp4`.
+    pub fn file_name(&self, stem: &str, suffix: &str) -> String {
+        let stem = self.sanitize(stem);
+        let stem = truncate_to_bytes(&stem, self.max_bytes.saturating_sub(suffix.len()));
+        // truncating may expose trailing dots or spaces again
+        let stem = match self.profile {
+            FilenameProfile::Posix => stem,
+            _ => stem.trim_end_matches(['.', ' ']),
+        };
+        let stem = if stem.is_empty() { "_" } else { stem };
+        format!("{stem}{suffix}")
+    }
+
+    /// Returns `dir/stem.ext`, or `dir/stem (1).ext`, `dir/stem (2).ext`... if it already exists.
+    pub fn unique_path(&self, dir: &Path, stem: &str, extension: &str) -> PathBuf {
+        let path = dir.join(self.file_name(stem, &format!(".{extension}")));
+        if !path.exists() {
+            return path;
+        }
+        (1..)
+            .map(|n| dir.join(self.file_name(stem, &format!(" ({n}).{extension}"))))
+            .find(|path| !path.exists())
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempdir::TempDir;
+
+    use super::{FilenameProfile, Sanitizer};
+
+    fn sanitizer(profile: FilenameProfile) -> Sanitizer {
+        Sanitizer {
+            profile,
+            max_bytes: 255,
+        }
+    }
+
+    #[test]
+    fn sanitize_with_profiles() {
+        let title = "【MV】a/b: c? \"d\" <e>*|.. ";
+        assert_eq!(
+            sanitizer(FilenameProfile::Posix).sanitize(title),
+            "【MV】a_b: c? \"d\" <e>*|.. "
+        );
+        assert_eq!(
+            sanitizer(FilenameProfile::Windows).sanitize(title),
+            "【MV】a_b_ c_ _d_ _e___"
+        );
+        assert_eq!(
+            sanitizer(FilenameProfile::Ascii).sanitize(title),
+            "_MV_a_b_ c_ _d_ _e___"
+        );
+        assert_eq!(
+            sanitizer(FilenameProfile::Windows).sanitize("con.txt"),
+            "con_.txt"
+        );
+        assert_eq!(sanitizer(FilenameProfile::Windows).sanitize("..."), "_");
+        assert_eq!(sanitizer(FilenameProfile::Posix).sanitize(".."), "_");
+    }
+
+    #[test]
+    fn truncate_on_char_boundary() {
+        let sanitizer = Sanitizer {
+            profile: FilenameProfile::Posix,
+            max_bytes: 20,
+        };
+        // each CJK character is 3 bytes, so only 5 of them fit before `.mp4`
+        assert_eq!(
+            sanitizer.file_name("保加利亚妖王视频合辑", ".mp4"),
+            "保加利亚妖.mp4"
+        );
+        assert_eq!(sanitizer.file_name("short", ".mp4"), "short.mp4");
+    }
+
+    #[test]
+    fn unique_path_on_collision() {
+        let dir = TempDir::new("sanitize").unwrap();
+        let sanitizer = sanitizer(FilenameProfile::Posix);
+        let first = sanitizer.unique_path(dir.path(), "a/b", "mp4");
+        assert_eq!(first, dir.path().join("a_b.mp4"));
+        fs::write(&first, "").unwrap();
+        let second = sanitizer.unique_path(dir.path(), "a/b", "mp4");
+        assert_eq!(second, dir.path().join("a_b (1).mp4"));
+        fs::write(&second, "").unwrap();
+        assert_eq!(
+            sanitizer.unique_path(dir.path(), "a/b", "mp4"),
+            dir.path().join("a_b (2).mp4")
+        );
+    }
+}
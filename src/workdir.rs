@@ -0,0 +1,218 @@
+use std::{
+    fs::{self, File},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::logger::Logger;
+
+/// Work directories and partial output files in use, removed by the Ctrl-C handler.
+static ACTIVE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Held locked by the process using a work directory, so that directories left by killed
+/// processes can be told apart from the ones in use.
+const LOCK_FILE: &str = ".lock";
+
+/// The system temp directory, each work directory gets a random name in it.
+pub fn default_root() -> PathBuf {
+    std::env::temp_dir()
+}
+
+fn set_active(path: &Path, active: bool) {
+    let mut paths = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+    if active {
+        paths.push(path.to_owned());
+    } else {
+        paths.retain(|p| p != path);
+    }
+}
+
+/// Removes the work directories and partial files in use and exits on Ctrl-C.
+pub fn handle_ctrlc() -> Result<()> {
+    ctrlc::set_handler(|| {
+        let active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+        for path in active.iter() {
+            let _ = if path.is_dir() {
+                fs::remove_dir_all(path)
+            } else {
+                fs::remove_file(path)
+            };
+        }
+        std::process::exit(130);
+    })?;
+    Ok(())
+}
+
+/// Whether `dir` is a work directory whose process is gone, i.e. its lock file is not locked.
+/// Symlinks and directories of other users, which cannot be opened, are never abandoned.
+fn is_abandoned(dir: &Path) -> bool {
+    if !fs::symlink_metadata(dir).is_ok_and(|m| m.is_dir()) {
+        return false;
+    }
+    // a missing lock file means the directory was just created and is about to be locked, or
+    // rarely that its process was killed right in between, which is left to the system cleanup
+    File::open(dir.join(LOCK_FILE)).is_ok_and(|lock| lock.try_lock().is_ok())
+}
+
+/// A directory for the intermediate files of one download, removed on drop.
+pub struct WorkDir<'a> {
+    path: PathBuf,
+    /// Locked until the directory is removed.
+    lock: Option<File>,
+    logger: &'a Logger,
+}
+
+impl<'a> WorkDir<'a> {
+    /// Creates a directory with a random name starting with `bilibili-downloader-<key>.` in
+    /// `root`. Directories of the same key left by killed runs are removed first, while the ones
+    /// of other running processes are kept.
+    pub fn create(root: &Path, key: &str, logger: &'a Logger) -> Result<Self> {
+        fs::create_dir_all(root)
+            .map_err(|e| anyhow!("failed to create {}: {e}", root.display()))?;
+        let prefix = format!("bilibili-downloader-{key}.");
+        for entry in fs::read_dir(root)?.map_while(Result::ok) {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with(&prefix) && is_abandoned(&path) {
+                logger.debug(&format!("remove abandoned {}", path.display()));
+                if let Err(e) = fs::remove_dir_all(&path) {
+                    logger.warn(&format!("无法删除临时目录 {}: {e}", path.display()));
+                }
+            }
+        }
+        let path = tempfile::Builder::new()
+            .prefix(&prefix)
+            .tempdir_in(root)
+            .map_err(|e| anyhow!("failed to create a directory in {}: {e}", root.display()))?
+            .into_path();
+        set_active(&path, true);
+        // removed on drop if locking fails
+        let mut work_dir = WorkDir {
+            path,
+            lock: None,
+            logger,
+        };
+        // locked under another name first, as an unlocked lock file marks the directory as
+        // abandoned
+        let new_lock = work_dir.join(".lock.new");
+        let lock = File::create(&new_lock)?;
+        lock.lock()?;
+        fs::rename(&new_lock, work_dir.join(LOCK_FILE))?;
+        work_dir.lock = Some(lock);
+        Ok(work_dir)
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for WorkDir<'_> {
+    fn drop(&mut self) {
+        set_active(&self.path, false);
+        // closed first, as open files cannot be removed on Windows
+        drop(self.lock.take());
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            if e.kind() != ErrorKind::NotFound {
+                self.logger
+                    .warn(&format!("无法删除临时目录 {}: {e}", self.path.display()));
+            }
+        }
+    }
+}
+
+/// Moves `from` to `to`, replacing it atomically. If they are on different filesystems,
+/// `from` is copied next to `to` first and then renamed.
+pub fn persist(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {}
+        Err(e) => return Err(anyhow!("failed to move to {}: {e}", to.display())),
+    }
+    let mut part = to.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    set_active(&part, true);
+    let result = fs::copy(from, &part).and_then(|_| fs::rename(&part, to));
+    set_active(&part, false);
+    if let Err(e) = result {
+        let _ = fs::remove_file(&part);
+        return Err(anyhow!("failed to copy to {}: {e}", to.display()));
+    }
+    fs::remove_file(from)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempdir::TempDir;
+
+    use super::{persist, WorkDir, ACTIVE};
+    use crate::logger::Logger;
+
+    #[test]
+    fn remove_on_drop() {
+        let root = TempDir::new("workdir").unwrap();
+        let logger = Logger::new(0);
+        let work_dir = WorkDir::create(root.path(), "BV17x411w7KC_279786", &logger).unwrap();
+        fs::write(work_dir.join("video.mp4"), "video").unwrap();
+        let path = work_dir.path.clone();
+        assert!(path.starts_with(root.path()));
+        assert!(ACTIVE.lock().unwrap().contains(&path));
+
+        drop(work_dir);
+        assert!(!path.exists());
+        assert!(!ACTIVE.lock().unwrap().contains(&path));
+    }
+
+    #[test]
+    fn keep_directories_in_use() {
+        let root = TempDir::new("workdir").unwrap();
+        let logger = Logger::new(0);
+        let key = "BV17x411w7KC_279786";
+        let abandoned = root
+            .path()
+            .join(format!("bilibili-downloader-{key}.killed"));
+        fs::create_dir_all(&abandoned).unwrap();
+        fs::write(abandoned.join(".lock"), "").unwrap();
+        fs::write(abandoned.join("video.mp4"), "stale").unwrap();
+        let unlocked = root
+            .path()
+            .join(format!("bilibili-downloader-{key}.creating"));
+        fs::create_dir_all(&unlocked).unwrap();
+
+        let first = WorkDir::create(root.path(), key, &logger).unwrap();
+        assert!(
+            !abandoned.exists(),
+            "directories of killed runs should be removed"
+        );
+        assert!(
+            unlocked.exists(),
+            "directories without a lock file yet should be kept"
+        );
+        fs::write(first.join("video.mp4"), "first").unwrap();
+        let second = WorkDir::create(root.path(), key, &logger).unwrap();
+        assert_ne!(first.path, second.path);
+        assert_eq!(
+            fs::read_to_string(first.join("video.mp4")).unwrap(),
+            "first",
+            "the directory of another download in progress should be kept"
+        );
+    }
+
+    #[test]
+    fn persist_replaces_target() {
+        let dir = TempDir::new("workdir").unwrap();
+        let from = dir.path().join("merged.mp4");
+        let to = dir.path().join("a.mp4");
+        fs::write(&from, "new").unwrap();
+        fs::write(&to, "old").unwrap();
+        persist(&from, &to).unwrap();
+        assert_eq!(fs::read_to_string(&to).unwrap(), "new");
+        assert!(!from.exists());
+    }
+}